@@ -1,18 +1,18 @@
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use minifb::{Key, Scale, ScaleMode, Window, WindowOptions};
 
-use triple_buffer::Output;
-
 use tokio::task::{self, JoinHandle};
 
-use tracing::error;
+use tracing::{debug, info};
 
 use crate::audio::AudioConfig;
-use crate::audio::backend::{AudioBackend, AudioPacket};
-#[cfg(target_os = "windows")]
-use crate::audio::wasapi::WasapiBackend as Backend;
+#[cfg(feature = "file")]
+use crate::audio::recorder::{RecordOptions, Recorder};
+use crate::audio::registry::{self, BackendOptions};
+use crate::audio::supervisor::{AudioReceiver, Supervisor};
 
 use crate::graphics::renderer::Renderer;
 
@@ -20,18 +20,28 @@ use crate::visualisation::visualiser::Visualiser;
 
 const DEFAULT_WIDTH: usize = 1400;
 const DEFAULT_HEIGHT: usize = 600;
+// how often capture to photon latency is logged
+const LATENCY_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct App {
   window: Window,
   renderer: Renderer,
   visualiser: Visualiser,
-  audio_rx: Output<AudioPacket>,
+  audio_rx: AudioReceiver,
+  #[cfg(feature = "file")]
+  recorder: Recorder,
+  config: AudioConfig,
   audio_handle: Option<JoinHandle<()>>,
   stop: Arc<AtomicBool>,
+  latency_logged: Instant,
 }
 
 impl App {
-  pub fn new(config: AudioConfig) -> Result<Self, anyhow::Error> {
+  pub fn new(
+    config: AudioConfig,
+    options: &BackendOptions,
+    #[cfg(feature = "file")] record: &RecordOptions,
+  ) -> Result<Self, anyhow::Error> {
     // create window
     let window_options = WindowOptions {
       resize: true,
@@ -41,33 +51,32 @@ impl App {
     };
     let window = Window::new("a field", DEFAULT_WIDTH, DEFAULT_HEIGHT, window_options)?;
 
-    #[cfg(not(target_os = "windows"))]
-    compile_error!("windows only for now...");
-
-    // create audio backend...
+    // create audio backend and spawn its supervised task...
     let stop = Arc::new(AtomicBool::new(false));
-    let audio_backend = Backend::new(config.clone(), Arc::clone(&stop));
-
-    // create channel for audio packets
-    let (audio_tx, audio_rx) = triple_buffer::triple_buffer(&AudioPacket::default());
-
-    // spawn audio capture task
-    let audio_handle = tokio::spawn(async move {
-      if let Err(e) = audio_backend.run(audio_tx).await {
-        error!("audio capture error - {}", e);
-      }
-    });
+    let (entry, audio_backend) = registry::create(options, config.clone(), Arc::clone(&stop))?;
+    info!("using {} audio backend...", entry.name);
+    #[cfg_attr(not(feature = "file"), allow(unused_mut))]
+    let (mut supervisor, audio_rx) =
+      Supervisor::new(entry, options.clone(), config.clone(), Arc::clone(&stop));
+    // the recorder taps the capture on its own thread, before it's resampled
+    #[cfg(feature = "file")]
+    let recorder = Recorder::new(supervisor.capture_reader(), record)?;
+    let audio_handle = tokio::spawn(supervisor.run(audio_backend));
 
     let renderer = Renderer::new(DEFAULT_WIDTH, DEFAULT_HEIGHT);
-    let visualiser = Visualiser::new(config, DEFAULT_WIDTH);
+    let visualiser = Visualiser::new(config.clone(), DEFAULT_WIDTH);
 
     Ok(Self {
       window,
       renderer,
       visualiser,
       audio_rx,
+      #[cfg(feature = "file")]
+      recorder,
+      config,
       audio_handle: Some(audio_handle),
       stop,
+      latency_logged: Instant::now(),
     })
   }
 
@@ -75,13 +84,20 @@ impl App {
     self.window.set_target_fps(60);
 
     while self.window.is_open() && !self.window.is_key_down(Key::Escape) {
+      // the supervisor only gives up on eof, nothing left to show
+      if self.audio_finished() {
+        break;
+      }
       // observe current window size...
       let (width, height) = self.window.get_size();
       // process user inputs...
       self.handle_input();
-      // process audio packet living in buffer...
-      let packet = self.audio_rx.read();
-      self.visualiser.update(packet);
+      // analyse every hop captured since the last frame...
+      let hop = self.config.hop_size;
+      self
+        .audio_rx
+        .read(hop, |packet| self.visualiser.update(packet));
+      self.visualiser.set_connection(self.audio_rx.state());
       // live resize if the dimensions changed
       self.resize(width, height);
       // render a frame...
@@ -90,6 +106,7 @@ impl App {
       self
         .window
         .update_with_buffer(self.renderer.buffer(), width, height)?;
+      self.log_latency();
       // yield to other tasks
       task::yield_now().await;
     }
@@ -102,8 +119,36 @@ impl App {
     self.visualiser.resize(width);
   }
 
+  /// Time from capturing the newest sample shown to presenting it
+  fn log_latency(&mut self) {
+    if self.latency_logged.elapsed() < LATENCY_INTERVAL {
+      return;
+    }
+    if let Some(captured) = self.visualiser.captured() {
+      debug!(
+        "capture to photon latency {:.1}ms, {} audio packets dropped...",
+        captured.elapsed().as_secs_f64() * 1000.0,
+        self.audio_rx.dropped()
+      );
+    }
+    self.latency_logged = Instant::now();
+  }
+
+  fn audio_finished(&self) -> bool {
+    self
+      .audio_handle
+      .as_ref()
+      .is_none_or(|handle| handle.is_finished())
+  }
+
   fn handle_input(&mut self) {
-    // stub
+    #[cfg(feature = "file")]
+    if self.window.is_key_pressed(Key::R, minifb::KeyRepeat::No) {
+      let recording = self.recorder.toggle();
+      info!("recording {}...", if recording { "on" } else { "off" });
+    }
+    #[cfg(feature = "file")]
+    self.visualiser.set_recording(self.recorder.is_recording());
   }
 }
 
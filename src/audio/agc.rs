@@ -0,0 +1,158 @@
+use std::time::Duration;
+
+// gain never drops below this, enough for a full scale sine at any fft size
+const MIN_GAIN: f32 = 1e-4;
+// levels under this are treated as silence and leave the gain alone
+const SILENCE_FLOOR: f32 = 1e-6;
+
+/// How the automatic gain control follows the signal
+#[derive(Clone, Copy, Debug)]
+pub struct AgcConfig {
+  /// Level the rms of the bars is brought to, 1.0 being the top of the
+  /// display
+  pub target: f32,
+  /// Time taken to come down to louder input
+  pub attack: Duration,
+  /// Time taken to come back up once it gets quieter
+  pub release: Duration,
+  /// Most the signal is ever boosted by, as a factor
+  pub max_gain: f32,
+}
+
+/// Automatic gain control over spectrum magnitudes, much like cava's
+/// autosens.
+///
+/// Follows the rms over the followed channel's bars with an envelope that
+/// rises quickly and falls slowly, in decibels so both take the same time
+/// whatever the level, and scales everything so that envelope sits at the
+/// target. The envelope averages over time, so a transient or a lone tone
+/// only nudges it.
+/// Silence holds the gain where it is rather than winding it up to the
+/// maximum, so music fading back in doesn't come in pinned.
+pub struct AutoGain {
+  config: AgcConfig,
+  // followed level in db, none until we have heard something
+  envelope: Option<f32>,
+  gain: f32,
+}
+
+impl AutoGain {
+  pub fn new(config: AgcConfig) -> Self {
+    Self {
+      config,
+      envelope: None,
+      gain: 1.0,
+    }
+  }
+
+  /// Gain to apply to the magnitudes, as a factor
+  pub fn gain(&self) -> f32 {
+    self.gain
+  }
+
+  /// Follow `level`, the bars' rms before gain over the last `elapsed`
+  pub fn update(&mut self, level: f32, elapsed: Duration) {
+    if level.is_nan() || level <= SILENCE_FLOOR {
+      return;
+    }
+    let level_db = 20.0 * level.log10();
+    let envelope = match self.envelope {
+      // start out right where the signal is
+      None => level_db,
+      Some(envelope) => {
+        let time = if level_db > envelope {
+          self.config.attack
+        } else {
+          self.config.release
+        };
+        envelope + (level_db - envelope) * coefficient(elapsed, time)
+      }
+    };
+    self.envelope = Some(envelope);
+
+    let target_db = 20.0 * self.config.target.log10();
+    self.gain = 10.0f32
+      .powf((target_db - envelope) / 20.0)
+      .clamp(MIN_GAIN, self.config.max_gain.max(MIN_GAIN));
+  }
+}
+
+/// How far a one pole follower with time constant `time` moves in `elapsed`
+fn coefficient(elapsed: Duration, time: Duration) -> f32 {
+  if time.is_zero() {
+    return 1.0;
+  }
+  1.0 - (-elapsed.as_secs_f32() / time.as_secs_f32()).exp()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const STEP: Duration = Duration::from_millis(10);
+
+  fn auto_gain() -> AutoGain {
+    AutoGain::new(AgcConfig {
+      target: 0.5,
+      attack: Duration::from_millis(100),
+      release: Duration::from_millis(1000),
+      max_gain: 100.0,
+    })
+  }
+
+  /// Follow `level` for `time`, returning where the gain ends up in db
+  fn hold(agc: &mut AutoGain, level: f32, time: Duration) -> f32 {
+    for _ in 0..time.as_millis() / STEP.as_millis() {
+      agc.update(level, STEP);
+    }
+    20.0 * agc.gain().log10()
+  }
+
+  #[test]
+  fn starts_out_at_the_target() {
+    let mut agc = auto_gain();
+    agc.update(0.05, STEP);
+    assert!((agc.gain() - 10.0).abs() < 1e-3, "{}", agc.gain());
+  }
+
+  #[test]
+  fn attack_comes_down_most_of_the_way_in_its_time() {
+    // 20db of gain, then a signal at the target
+    let mut agc = auto_gain();
+    agc.update(0.05, STEP);
+    let db = hold(&mut agc, 0.5, Duration::from_millis(100));
+    let expected = 20.0 * (-1.0f32).exp();
+    assert!((db - expected).abs() < 0.1, "{} db", db);
+  }
+
+  #[test]
+  fn release_comes_back_up_slower() {
+    let mut agc = auto_gain();
+    agc.update(0.5, STEP);
+    // an attack's time only moves it a tenth of the release's way
+    let db = hold(&mut agc, 0.05, Duration::from_millis(100));
+    let expected = 20.0 * (1.0 - (-0.1f32).exp());
+    assert!((db - expected).abs() < 0.1, "{} db", db);
+    let db = hold(&mut agc, 0.05, Duration::from_millis(900));
+    let expected = 20.0 * (1.0 - (-1.0f32).exp());
+    assert!((db - expected).abs() < 0.1, "{} db", db);
+  }
+
+  #[test]
+  fn never_boosts_past_the_max() {
+    let mut agc = auto_gain();
+    agc.update(1e-4, STEP);
+    assert_eq!(agc.gain(), 100.0);
+  }
+
+  #[test]
+  fn silence_holds_the_gain() {
+    let mut agc = auto_gain();
+    agc.update(0.05, STEP);
+    let gain = agc.gain();
+    for level in [0.0, 1e-7, f32::NAN] {
+      hold(&mut agc, level, Duration::from_secs(10));
+      assert_eq!(agc.gain(), gain);
+    }
+  }
+}
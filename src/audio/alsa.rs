@@ -0,0 +1,226 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use ::alsa::device_name::HintIter;
+use ::alsa::pcm::{Access, Format, Frames, HwParams, PCM};
+use ::alsa::{Direction, ValueOr};
+
+use async_trait::async_trait;
+
+use tokio::task;
+
+use tracing::{info, warn};
+
+use crate::audio::AudioConfig;
+use crate::audio::backend::{AudioBackend, AudioPacket, AudioSender, DeviceInfo};
+use crate::audio::format::{Endian, SampleFormat, SampleType};
+
+/// Captures straight from an alsa pcm device (e.g. `hw:Loopback,1`), for
+/// machines without a sound server.
+///
+/// Rate and channel count are requests, the device may settle on the nearest
+/// it supports. Overruns are recovered in place rather than ending capture.
+pub struct AlsaBackend {
+  config: AudioConfig,
+  device: String,
+  sample_rate: u32,
+  channels: u16,
+  stop: Arc<AtomicBool>,
+}
+
+impl AlsaBackend {
+  pub fn new(
+    config: AudioConfig,
+    device: String,
+    sample_rate: u32,
+    channels: u16,
+    stop: Arc<AtomicBool>,
+  ) -> Self {
+    Self {
+      config,
+      device,
+      sample_rate,
+      channels,
+      stop,
+    }
+  }
+
+  /// Check that `device` can be opened for capture
+  pub fn probe(device: &str) -> Result<(), anyhow::Error> {
+    PCM::new(device, Direction::Capture, false)?;
+    Ok(())
+  }
+
+  /// Pcm names alsa hints at for capture. The format is only known once a
+  /// device is opened, so rate and channels are left out
+  pub fn devices() -> Result<Vec<DeviceInfo>, anyhow::Error> {
+    let devices = HintIter::new_str(None, "pcm")?
+      .filter(|hint| hint.direction.is_none_or(|d| d == Direction::Capture))
+      .filter_map(|hint| {
+        let id = hint.name?;
+        // the snd-aloop card is how alsa-only setups capture their output
+        let loopback = id.contains("Loopback");
+        Some(DeviceInfo {
+          // descriptions span lines, the first is the card name
+          name: hint
+            .desc
+            .as_deref()
+            .and_then(|desc| desc.lines().next())
+            .unwrap_or(&id)
+            .to_owned(),
+          is_default: id == "default",
+          id,
+          loopback,
+          sample_rate: None,
+          channels: None,
+        })
+      })
+      .collect();
+    Ok(devices)
+  }
+}
+
+#[async_trait]
+impl AudioBackend for AlsaBackend {
+  async fn run(self: Box<Self>, tx: AudioSender) -> Result<(), anyhow::Error> {
+    task::spawn_blocking(move || capture_loop(*self, tx)).await??;
+    Ok(())
+  }
+}
+
+/// Sample formats we accept from the device in order of preference, and how
+/// to decode each
+const FORMATS: [(Format, SampleFormat); 5] = [
+  (
+    Format::FloatLE,
+    SampleFormat::new(SampleType::F32, Endian::Little),
+  ),
+  (
+    Format::S32LE,
+    SampleFormat::new(SampleType::I32, Endian::Little),
+  ),
+  (
+    Format::S243LE,
+    SampleFormat::new(SampleType::I24, Endian::Little),
+  ),
+  (
+    Format::S24LE,
+    SampleFormat::new(SampleType::I24In32, Endian::Little),
+  ),
+  (
+    Format::S16LE,
+    SampleFormat::new(SampleType::I16, Endian::Little),
+  ),
+];
+
+fn capture_loop(backend: AlsaBackend, mut tx: AudioSender) -> Result<(), anyhow::Error> {
+  // 10ms periods keep the stop flag responsive
+  const PERIOD_MS: u32 = 10;
+
+  let pcm = PCM::new(&backend.device, Direction::Capture, false)?;
+
+  // negotiate hardware params
+  {
+    let hwp = HwParams::any(&pcm)?;
+    hwp.set_access(Access::RWInterleaved)?;
+    let (format, _) = FORMATS
+      .into_iter()
+      .find(|(f, _)| hwp.test_format(*f).is_ok())
+      .ok_or_else(|| anyhow::anyhow!("{} supports none of f32, s32, s24 or s16", backend.device))?;
+    hwp.set_format(format)?;
+    hwp.set_channels_near(backend.channels as u32)?;
+    let rate = hwp.set_rate_near(backend.sample_rate, ValueOr::Nearest)?;
+    let period = (rate * PERIOD_MS / 1000) as Frames;
+    hwp.set_period_size_near(period, ValueOr::Nearest)?;
+    // leave room for a full analysis buffer on top of a few periods
+    let buffer = (backend.config.buffer_size as Frames).max(period * 4);
+    hwp.set_buffer_size_near(buffer)?;
+    pcm.hw_params(&hwp)?;
+  }
+
+  // read back what the device actually agreed to
+  let hwp = pcm.hw_params_current()?;
+  let format = hwp.get_format()?;
+  let (_, sample_format) = FORMATS
+    .into_iter()
+    .find(|(f, _)| *f == format)
+    .ok_or_else(|| anyhow::anyhow!("{} settled on unexpected format {}", backend.device, format))?;
+  let sample_rate = hwp.get_rate()?;
+  let channels = hwp.get_channels()? as u16;
+  let period = hwp.get_period_size()? as usize;
+  drop(hwp);
+
+  info!(
+    "alsa capture started on {} ({} Hz, {} channels, {}, {} frame periods)...",
+    backend.device, sample_rate, channels, format, period
+  );
+
+  pcm.start()?;
+
+  let stream = Stream {
+    pcm: &pcm,
+    sample_rate,
+    channels,
+    period,
+    format: sample_format,
+  };
+  stream.capture(&backend, &mut tx)?;
+
+  let _ = pcm.drop();
+  info!("alsa capture stopped...");
+  Ok(())
+}
+
+/// A started pcm and the parameters it was negotiated with
+struct Stream<'a> {
+  pcm: &'a PCM,
+  sample_rate: u32,
+  channels: u16,
+  period: usize,
+  format: SampleFormat,
+}
+
+impl Stream<'_> {
+  /// Read periods until stopped, decoding them as `format`
+  fn capture(&self, backend: &AlsaBackend, tx: &mut AudioSender) -> Result<(), anyhow::Error> {
+    let io = self.pcm.io_bytes();
+    let channels = self.channels as usize;
+    let frame_bytes = channels * self.format.bytes_per_sample();
+    let mut raw = vec![0u8; self.period * frame_bytes];
+    let mut packet = AudioPacket::new(
+      self.sample_rate as f32,
+      self.channels,
+      self.period * channels,
+    );
+
+    while !backend.stop.load(Ordering::Relaxed) {
+      let frames = match io.readi(&mut raw) {
+        Ok(frames) => frames,
+        Err(e) if e.errno() == libc::EPIPE || e.errno() == libc::ESTRPIPE => {
+          // overrun or suspend, samples were lost but the device is fine
+          warn!("alsa xrun on {}, recovering...", backend.device);
+          self.pcm.try_recover(e, true)?;
+          packet.discontinuity = true;
+          continue;
+        }
+        Err(e) => return Err(e.into()),
+      };
+      if frames == 0 {
+        continue;
+      }
+
+      packet.samples.clear();
+      self
+        .format
+        .decode_into(&raw[..frames * frame_bytes], &mut packet.samples);
+      packet.is_silent = packet.samples.iter().all(|&s| s == 0.0);
+      // readi returns as soon as the last frame lands
+      packet.timestamp = Instant::now() - packet.duration();
+
+      tx.write(&mut packet);
+    }
+
+    Ok(())
+  }
+}
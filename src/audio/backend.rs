@@ -1,4 +1,9 @@
-use triple_buffer::Input;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::audio::resampler::Resampler;
+use crate::audio::ring::{RingFormat, RingMark, RingWriter};
 
 #[derive(Clone)]
 pub struct AudioPacket {
@@ -6,6 +11,38 @@ pub struct AudioPacket {
   pub sample_rate: f32,
   pub channels: u16,
   pub is_silent: bool,
+  /// When the first frame was captured, on the monotonic clock
+  pub timestamp: Instant,
+  /// Stream position of the first frame, in frames
+  pub position: u64,
+  /// Counts up by one per packet over a backend's run
+  pub sequence: u64,
+  /// Audio was lost right before this packet, or the stream restarted
+  pub discontinuity: bool,
+  /// The source flagged this packet's timing or data as unreliable
+  pub glitch: bool,
+}
+
+impl AudioPacket {
+  /// Empty packet for a backend to fill and send over and over
+  pub fn new(sample_rate: f32, channels: u16, capacity: usize) -> Self {
+    Self {
+      samples: Vec::with_capacity(capacity),
+      sample_rate,
+      channels,
+      is_silent: false,
+      ..Default::default()
+    }
+  }
+
+  pub fn frames(&self) -> usize {
+    self.samples.len() / self.channels.max(1) as usize
+  }
+
+  /// How long the packet lasts
+  pub fn duration(&self) -> Duration {
+    Duration::from_secs_f64(self.frames() as f64 / self.sample_rate.max(1.0) as f64)
+  }
 }
 
 impl Default for AudioPacket {
@@ -15,12 +52,242 @@ impl Default for AudioPacket {
       sample_rate: 0.0,
       channels: 0,
       is_silent: true,
+      timestamp: Instant::now(),
+      position: 0,
+      sequence: 0,
+      discontinuity: false,
+      glitch: false,
     }
   }
 }
 
+/// A capture device a backend can open by `id`
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+  pub id: String,
+  pub name: String,
+  // hears what the machine plays rather than what comes in from outside
+  pub loopback: bool,
+  pub is_default: bool,
+  // none where the backend can't tell without opening the device
+  pub sample_rate: Option<u32>,
+  pub channels: Option<u16>,
+}
+
+/// Where a backend sends what it captures, for the length of one run
+pub struct AudioSender {
+  ring: RingWriter,
+  // rate the ring is fed at whatever the backend's is, none passes through
+  analysis_rate: Option<f32>,
+  resampler: Option<Resampler>,
+  resampled: Vec<f32>,
+  // also gets every packet as captured, before any resampling
+  capture: Option<RingWriter>,
+  // sequence and flags still to go out with the next samples, held over
+  // when the resampler swallows a packet whole
+  pending_sequence: Option<u64>,
+  pending_discontinuity: bool,
+  pending_glitch: bool,
+}
+
+impl AudioSender {
+  pub fn new(ring: RingWriter, analysis_rate: Option<u32>) -> Self {
+    // the ring already holds an earlier run, which we don't carry on from
+    let restarted = ring.format().is_some();
+    Self {
+      ring,
+      analysis_rate: analysis_rate.map(|rate| rate as f32),
+      resampler: None,
+      resampled: Vec::new(),
+      capture: None,
+      pending_sequence: None,
+      pending_discontinuity: restarted,
+      pending_glitch: false,
+    }
+  }
+
+  /// Also write every packet into `capture` as it comes, at its own rate
+  #[cfg_attr(not(feature = "file"), allow(dead_code))]
+  pub fn with_capture(mut self, capture: Option<RingWriter>) -> Self {
+    self.capture = capture;
+    self
+  }
+
+  /// Set up ahead for packets of up to `frames` frames at this rate and
+  /// layout, so writing them never allocates, for backends writing from a
+  /// realtime thread
+  #[cfg_attr(not(feature = "jack"), allow(dead_code))]
+  pub fn prepare(&mut self, sample_rate: f32, channels: u16, frames: usize) {
+    if let Some(rate) = self.analysis_rate
+      && rate != sample_rate
+      && sample_rate > 0.0
+    {
+      // kept if it's already running at this rate, so preparing again for a
+      // new size doesn't break the stream
+      let resampler = match &mut self.resampler {
+        Some(resampler) if resampler.matches(sample_rate, channels) => resampler,
+        slot => slot.insert(Resampler::new(sample_rate, rate, channels)),
+      };
+      let samples = resampler.reserve(frames);
+      self.resampled.reserve(samples);
+    }
+  }
+
+  /// Queue the packet for analysis, nothing is dropped until the ring's
+  /// history is full.
+  ///
+  /// The packet then moves on to the next one, its sequence number and
+  /// position advancing and its flags clearing, so backends only fill in the
+  /// samples, timestamp and whatever flags their source reports.
+  pub fn write(&mut self, packet: &mut AudioPacket) {
+    let sequence = *self.pending_sequence.get_or_insert(packet.sequence);
+    self.pending_discontinuity |= packet.discontinuity;
+    self.pending_glitch |= packet.glitch;
+
+    let mut mark = RingMark {
+      timestamp: packet.timestamp,
+      position: packet.position,
+      sequence,
+      discontinuity: false,
+      glitch: false,
+    };
+    if let Some(capture) = &mut self.capture {
+      let format = RingFormat {
+        sample_rate: packet.sample_rate,
+        channels: packet.channels,
+      };
+      let mark = RingMark {
+        sequence: packet.sequence,
+        discontinuity: packet.discontinuity,
+        glitch: packet.glitch,
+        ..mark
+      };
+      capture.write(&packet.samples, format, mark);
+    }
+    let (samples, sample_rate) = match self.analysis_rate {
+      // bypassed when the rates already match
+      Some(rate) if rate != packet.sample_rate && packet.sample_rate > 0.0 => {
+        let resampler = match &mut self.resampler {
+          Some(resampler) if resampler.matches(packet.sample_rate, packet.channels) => resampler,
+          slot => slot.insert(Resampler::new(packet.sample_rate, rate, packet.channels)),
+        };
+        self.resampled.clear();
+        let offset = resampler.process(&packet.samples, &mut self.resampled);
+
+        // the filter delays things a little, and positions count output frames
+        let shift = Duration::from_secs_f64(offset.abs() / packet.sample_rate as f64);
+        mark.timestamp = if offset >= 0.0 {
+          packet.timestamp + shift
+        } else {
+          packet.timestamp - shift
+        };
+        let ratio = rate as f64 / packet.sample_rate as f64;
+        mark.position = ((packet.position as f64 + offset) * ratio).round().max(0.0) as u64;
+        (&self.resampled, rate)
+      }
+      _ => {
+        self.resampler = None;
+        (&packet.samples, packet.sample_rate)
+      }
+    };
+
+    if !samples.is_empty() {
+      let format = RingFormat {
+        sample_rate,
+        channels: packet.channels,
+      };
+      self.pending_sequence = None;
+      mark.discontinuity = std::mem::take(&mut self.pending_discontinuity);
+      mark.glitch = std::mem::take(&mut self.pending_glitch);
+      self.ring.write(samples, format, mark);
+    }
+
+    packet.sequence += 1;
+    packet.position += packet.frames() as u64;
+    packet.discontinuity = false;
+    packet.glitch = false;
+  }
+}
+
+/// A source of audio packets, boxed so the registry can pick one at runtime
+#[async_trait]
 pub trait AudioBackend: Send {
-  type Error;
+  /// Write packets into `tx` until stopped, or until the source runs out
+  async fn run(self: Box<Self>, tx: AudioSender) -> Result<(), anyhow::Error>;
+}
+
+/// Holds a synthetic source to real-time pace, one packet at a time
+#[cfg(any(feature = "file", feature = "generator"))]
+pub struct Pacer {
+  packet_duration: Duration,
+  deadline: Instant,
+}
+
+#[cfg(any(feature = "file", feature = "generator"))]
+impl Pacer {
+  pub fn new(frames_per_packet: usize, sample_rate: u32) -> Self {
+    Self {
+      packet_duration: Duration::from_secs_f64(frames_per_packet as f64 / sample_rate as f64),
+      deadline: Instant::now(),
+    }
+  }
 
-  async fn run(self, tx: Input<AudioPacket>) -> Result<(), Self::Error>;
+  /// Block until the next packet is due, returning when that was
+  pub fn wait(&mut self) -> Instant {
+    let now = Instant::now();
+    if self.deadline > now {
+      std::thread::sleep(self.deadline - now);
+    } else if now - self.deadline > self.packet_duration * 4 {
+      // we fell far behind (suspended, debugger...), resync instead of bursting
+      self.deadline = now;
+    }
+    let due = self.deadline;
+    self.deadline += self.packet_duration;
+    due
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audio::ring::SampleRing;
+
+  #[test]
+  fn matching_rate_passes_through() {
+    let ring = SampleRing::new(1024);
+    let mut reader = ring.reader();
+    let mut tx = AudioSender::new(ring.writer(), Some(48_000));
+
+    let samples: Vec<f32> = (0..256).map(|n| (n as f32 * 0.1).sin()).collect();
+    let mut packet = AudioPacket::new(48_000.0, 2, samples.len());
+    packet.samples.extend_from_slice(&samples);
+    tx.write(&mut packet);
+
+    let mut out = Vec::new();
+    let read = reader.next(128, 128, &mut out).unwrap();
+    assert_eq!(out, samples);
+    assert_eq!(read.format.sample_rate, 48_000.0);
+    assert_eq!(read.format.channels, 2);
+  }
+
+  #[test]
+  fn other_rates_are_resampled() {
+    let ring = SampleRing::new(4096);
+    let mut reader = ring.reader();
+    let mut tx = AudioSender::new(ring.writer(), Some(48_000));
+    tx.prepare(44_100.0, 1, 441);
+
+    let mut packet = AudioPacket::new(44_100.0, 1, 441);
+    for _ in 0..10 {
+      packet.samples.clear();
+      packet.samples.resize(441, 0.5);
+      tx.write(&mut packet);
+    }
+
+    let mut out = Vec::new();
+    let read = reader.next(4000, 4000, &mut out).unwrap();
+    assert_eq!(read.format.sample_rate, 48_000.0);
+    // steady once the filter has filled
+    assert!(out[100..].iter().all(|s| (s - 0.5).abs() < 1e-3));
+  }
 }
@@ -0,0 +1,428 @@
+use std::collections::BTreeMap;
+use std::f32::consts::PI;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use realfft::{RealFftPlanner, RealToComplex};
+use rustfft::FftPlanner;
+use rustfft::num_complex::Complex;
+use tracing::warn;
+
+use crate::audio::processor::History;
+use crate::audio::window::{WindowFunction, enbw};
+
+// longest kernel, the lowest bins get wider rather than slower past this
+const MAX_KERNEL: usize = 1 << 16;
+const MIN_FFT: usize = 64;
+// spectral kernel values under this fraction of their peak are left out
+const SPARSITY: f32 = 1e-3;
+// how many times over each kernel's length it is worked out again, the short
+// treble kernels every hop and the long bass ones only as often as they need
+const OVERLAP: usize = 16;
+
+/// Constant-q transform, bins spaced evenly in pitch with `bins_per_octave` to
+/// the octave and each as wide as the gap to the next, so the bass gets fine
+/// frequency detail and the treble fast response.
+///
+/// Each bin correlates the newest audio with a windowed tone of its own
+/// frequency, so every bin's window holds the same number of cycles. Following
+/// brown and puckette, that is done in the frequency domain against sparse
+/// spectral kernels, with kernels of about the same length sharing an fft over
+/// the tail of the signal.
+pub struct ConstantQ {
+  bins_per_octave: f32,
+  min_freq: f32,
+  sample_rate: f32,
+  bins: usize,
+  // frames of signal the longest kernel needs
+  len: usize,
+  // noise bandwidth of each bin, in bins
+  enbw: f32,
+  groups: Vec<KernelGroup>,
+}
+
+/// Kernels sharing an fft length
+struct KernelGroup {
+  fft: Arc<dyn RealToComplex<f32>>,
+  input: Vec<f32>,
+  spectrum: Vec<Complex<f32>>,
+  scratch: Vec<Complex<f32>>,
+  // worked out every this many hops
+  every: u64,
+  kernels: Vec<Kernel>,
+}
+
+/// One bin's spectral kernel, conjugated and scaled so a full scale sine at
+/// the bin's frequency comes out as 1.0
+struct Kernel {
+  bin: usize,
+  // first fft bin of the kernel
+  start: usize,
+  weights: Vec<Complex<f32>>,
+}
+
+impl ConstantQ {
+  pub fn new(
+    bins_per_octave: u32,
+    min_freq: f32,
+    max_freq: f32,
+    window: WindowFunction,
+    sample_rate: f32,
+    hop: usize,
+  ) -> Self {
+    let per_octave = bins_per_octave.max(1) as f32;
+    let q = 1.0 / (2f32.powf(1.0 / per_octave) - 1.0);
+    let top = max_freq.min(sample_rate / 2.0);
+    let bins = ((per_octave * (top / min_freq).log2()).floor() as usize + 1).max(1);
+
+    let mut real_planner = RealFftPlanner::<f32>::new();
+    let mut planner = FftPlanner::<f32>::new();
+    let mut groups: BTreeMap<usize, KernelGroup> = BTreeMap::new();
+    for bin in 0..bins {
+      let freq = min_freq * 2f32.powf(bin as f32 / per_octave);
+      let length = ((q * sample_rate / freq).round() as usize).clamp(2, MAX_KERNEL);
+      let fft_len = length.next_power_of_two().max(MIN_FFT);
+      let group = groups.entry(fft_len).or_insert_with(|| {
+        let fft = real_planner.plan_fft_forward(fft_len);
+        KernelGroup {
+          input: fft.make_input_vec(),
+          spectrum: fft.make_output_vec(),
+          scratch: fft.make_scratch_vec(),
+          every: (fft_len / (OVERLAP * hop.max(1))).max(1) as u64,
+          fft,
+          kernels: Vec::new(),
+        }
+      });
+      let coefficients = window.coefficients(length);
+      group.kernels.push(Kernel::new(
+        bin,
+        freq / sample_rate,
+        &coefficients,
+        fft_len,
+        &mut planner,
+      ));
+    }
+
+    Self {
+      bins_per_octave: per_octave,
+      min_freq,
+      sample_rate,
+      bins,
+      len: groups.keys().last().copied().unwrap_or(MIN_FFT),
+      enbw: enbw(&window.coefficients(MIN_FFT)),
+      groups: groups.into_values().collect(),
+    }
+  }
+
+  /// Frames of signal the transform looks back over
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn bins(&self) -> usize {
+    self.bins
+  }
+
+  /// Noise bandwidth of each bin, in bins
+  pub fn enbw(&self) -> f32 {
+    self.enbw
+  }
+
+  /// Where `freq` falls among the bins, fractionally
+  pub fn position(&self, freq: f32) -> f32 {
+    self.bins_per_octave * (freq.max(f32::MIN_POSITIVE) / self.min_freq).log2()
+  }
+
+  /// Amplitude of each bin over the end of `signal` into `out`, 1.0 being a
+  /// full scale sine. Bins whose turn it isn't on hop `tick` keep what `out`
+  /// last had, unless `out` is new
+  pub fn process(&mut self, signal: &History, tick: u64, out: &mut Vec<f32>) {
+    let fresh = out.len() != self.bins;
+    if fresh {
+      out.clear();
+      out.resize(self.bins, 0.0);
+    }
+    for group in &mut self.groups {
+      if !fresh && !tick.is_multiple_of(group.every) {
+        continue;
+      }
+      signal.copy_newest(&mut group.input);
+      group
+        .fft
+        .process_with_scratch(&mut group.input, &mut group.spectrum, &mut group.scratch)
+        .expect("fft forward failed");
+
+      for kernel in &group.kernels {
+        let sum: Complex<f32> = group.spectrum[kernel.start..]
+          .iter()
+          .zip(&kernel.weights)
+          .map(|(x, w)| x * w)
+          .sum();
+        out[kernel.bin] = sum.norm();
+      }
+    }
+  }
+}
+
+/// Constant-q transforms for each sample rate met, each built just the once
+/// and on a thread of its own, as the long kernels take a while
+pub struct ConstantQCache {
+  bins_per_octave: u32,
+  min_freq: f32,
+  max_freq: f32,
+  window: WindowFunction,
+  hop: usize,
+  // built and not in use
+  ready: Vec<ConstantQ>,
+  building: Vec<(f32, JoinHandle<ConstantQ>)>,
+}
+
+impl ConstantQCache {
+  pub fn new(
+    bins_per_octave: u32,
+    min_freq: f32,
+    max_freq: f32,
+    window: WindowFunction,
+    hop: usize,
+  ) -> Self {
+    Self {
+      bins_per_octave,
+      min_freq,
+      max_freq,
+      window,
+      hop,
+      ready: Vec::new(),
+      building: Vec::new(),
+    }
+  }
+
+  /// Start on the transform for `sample_rate`, unless it is built or on its
+  /// way
+  pub fn prepare(&mut self, sample_rate: f32) {
+    if self.ready.iter().any(|cq| cq.sample_rate == sample_rate)
+      || self.building.iter().any(|(rate, _)| *rate == sample_rate)
+    {
+      return;
+    }
+    let (bins_per_octave, min_freq, max_freq, window, hop) = (
+      self.bins_per_octave,
+      self.min_freq,
+      self.max_freq,
+      self.window,
+      self.hop,
+    );
+    let build = move || {
+      ConstantQ::new(
+        bins_per_octave,
+        min_freq,
+        max_freq,
+        window,
+        sample_rate,
+        hop,
+      )
+    };
+    match std::thread::Builder::new()
+      .name("constant-q".into())
+      .spawn(build)
+    {
+      Ok(handle) => self.building.push((sample_rate, handle)),
+      Err(e) => {
+        warn!(
+          "couldn't build the constant-q kernels on their own thread: {}",
+          e
+        );
+        self.ready.push(build());
+      }
+    }
+  }
+
+  /// The transform for `sample_rate` if it's built, to hand back with `put`
+  /// once done with
+  pub fn take(&mut self, sample_rate: f32) -> Option<ConstantQ> {
+    if let Some(i) = self
+      .ready
+      .iter()
+      .position(|cq| cq.sample_rate == sample_rate)
+    {
+      return Some(self.ready.swap_remove(i));
+    }
+    let i = self
+      .building
+      .iter()
+      .position(|(rate, handle)| *rate == sample_rate && handle.is_finished())?;
+    self.building.swap_remove(i).1.join().ok()
+  }
+
+  /// Keep a transform no longer in use, for when its rate comes back
+  pub fn put(&mut self, constant_q: ConstantQ) {
+    self.ready.push(constant_q);
+  }
+}
+
+impl Kernel {
+  /// A tone at `freq` cycles a sample under `window`, at the end of `fft_len`
+  /// frames so it follows the newest audio
+  fn new(
+    bin: usize,
+    freq: f32,
+    window: &[f32],
+    fft_len: usize,
+    planner: &mut FftPlanner<f32>,
+  ) -> Self {
+    let offset = fft_len - window.len();
+    // twice over the window's sum, as a real tone is half on each side
+    let scale = 2.0 / window.iter().sum::<f32>();
+    let mut buffer = vec![Complex::new(0.0, 0.0); fft_len];
+    for (m, &w) in window.iter().enumerate() {
+      let phase = 2.0 * PI * freq * m as f32;
+      buffer[offset + m] = Complex::from_polar(w * scale, phase);
+    }
+    planner.plan_fft_forward(fft_len).process(&mut buffer);
+
+    // keep the run around the peak, on the positive side the input has
+    let spectrum = &buffer[..=fft_len / 2];
+    let peak = spectrum.iter().map(|c| c.norm()).fold(0.0, f32::max);
+    let threshold = peak * SPARSITY;
+    let start = spectrum
+      .iter()
+      .position(|c| c.norm() >= threshold)
+      .unwrap_or(0);
+    let end = spectrum
+      .iter()
+      .rposition(|c| c.norm() >= threshold)
+      .map_or(start, |end| end + 1);
+    let weights = spectrum[start..end]
+      .iter()
+      .map(|c| c.conj() / fft_len as f32)
+      .collect();
+
+    Self {
+      bin,
+      start,
+      weights,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, Instant};
+
+  use super::*;
+  use crate::audio::filter::FilterChain;
+  use crate::audio::processor::{AudioProcessor, Channel, Magnitude, Transform};
+  use crate::audio::test_config;
+
+  const RATE: f32 = 48_000.0;
+
+  /// `frames` of a full scale sine at `freq`, from `start`
+  fn sine(freq: f32, start: usize, frames: usize) -> impl Iterator<Item = f32> {
+    (start..start + frames).map(move |n| (2.0 * PI * freq * n as f32 / RATE).sin())
+  }
+
+  #[test]
+  fn a_tone_on_a_bin_reads_full_scale_there() {
+    // the lowest kernels clamped to the longest allowed
+    let hop = 1024;
+    let mut constant_q = ConstantQ::new(24, 20.0, 2000.0, WindowFunction::Hann, RATE, hop);
+    assert!(constant_q.groups.iter().any(|g| g.every > 1));
+    let mut filters = FilterChain::new(&[], RATE);
+
+    for bin in [0, 30, 100, constant_q.bins() - 1] {
+      let freq = 20.0 * 2f32.powf(bin as f32 / 24.0);
+      let mut history = History::new(constant_q.len());
+      let mut out = Vec::new();
+      // a hop at a time, so the long kernels are skipped on most, until the
+      // longest is full and has had a turn since
+      let hops = constant_q.len() / hop + 16;
+      for tick in 0..hops {
+        history.push(sine(freq, tick * hop, hop), &mut filters);
+        constant_q.process(&history, tick as u64, &mut out);
+      }
+
+      let peak = (0..out.len())
+        .max_by(|&a, &b| out[a].total_cmp(&out[b]))
+        .unwrap();
+      assert_eq!(peak, bin, "{}hz", freq);
+      // within half a db
+      assert!(
+        (20.0 * out[bin].log10()).abs() < 0.5,
+        "{}hz read {}",
+        freq,
+        out[bin]
+      );
+    }
+  }
+
+  #[test]
+  fn kernels_are_taken_once_built() {
+    let mut cache = ConstantQCache::new(24, 20.0, 20_000.0, WindowFunction::Hann, 256);
+    assert!(cache.take(RATE).is_none());
+
+    cache.prepare(RATE);
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let constant_q = loop {
+      let finished = cache
+        .building
+        .iter()
+        .all(|(_, handle)| handle.is_finished());
+      match cache.take(RATE) {
+        Some(constant_q) => break constant_q,
+        None => assert!(!finished, "built but not taken"),
+      }
+      assert!(Instant::now() < deadline, "never built");
+      std::thread::sleep(Duration::from_millis(5));
+    };
+    assert!(cache.take(44_100.0).is_none());
+
+    // handed out the once, until it's put back
+    assert!(cache.take(RATE).is_none());
+    cache.put(constant_q);
+    assert!(cache.take(RATE).is_some());
+  }
+
+  #[test]
+  fn bars_stay_as_many_from_the_constant_q() {
+    let mut config = test_config();
+    config.transform = Transform::ConstantQ {
+      bins_per_octave: 12,
+    };
+    // calibrated, so the tone's neighbours can't clip level with it
+    config.magnitude = Magnitude::Decibels {
+      floor: -90.0,
+      ceiling: 10.0,
+    };
+    let bar_count = config.bar_count;
+    let edges = config
+      .scale
+      .edges(bar_count, config.min_freq, config.max_freq);
+    let bar = edges.windows(2).position(|e| e[1] > 1000.0).unwrap();
+    let freq = config.scale.centre(edges[bar], edges[bar + 1]);
+
+    let mut processor = AudioProcessor::new(config);
+    let block = 4096;
+    let mut played = 0;
+    let mut play = |processor: &mut AudioProcessor| {
+      let samples: Vec<f32> = sine(freq, played, block).collect();
+      processor.process(&samples, 1, RATE);
+      played += block;
+    };
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while !processor.constant_q_ready() {
+      assert!(Instant::now() < deadline, "never built");
+      play(&mut processor);
+      std::thread::sleep(Duration::from_millis(5));
+    }
+    // long enough to fill the longest kernel
+    for _ in 0..(1 << 17) / block {
+      play(&mut processor);
+    }
+
+    let bars = processor.spectrum(Channel::Mono);
+    assert_eq!(bars.len(), bar_count);
+    let peak = (0..bars.len())
+      .max_by(|&a, &b| bars[a].total_cmp(&bars[b]))
+      .unwrap();
+    assert_eq!(peak, bar);
+  }
+}
@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use hound::{SampleFormat, WavReader};
+
+use async_trait::async_trait;
+
+use tokio::task;
+
+use tracing::info;
+
+use crate::audio::AudioConfig;
+use crate::audio::backend::{AudioBackend, AudioPacket, AudioSender, Pacer};
+
+/// Plays a wav file into the packet stream at real-time pace.
+///
+/// When `looping` is false, the backend writes a final silent packet and
+/// returns once the file is exhausted, which is how the app learns of eof.
+pub struct FileBackend {
+  config: AudioConfig,
+  path: PathBuf,
+  looping: bool,
+  stop: Arc<AtomicBool>,
+}
+
+impl FileBackend {
+  pub fn new(config: AudioConfig, path: PathBuf, looping: bool, stop: Arc<AtomicBool>) -> Self {
+    Self {
+      config,
+      path,
+      looping,
+      stop,
+    }
+  }
+
+  /// Check that `path` opens as a wav file we can play
+  pub fn probe(path: &Path) -> Result<(), anyhow::Error> {
+    let spec = WavReader::open(path)?.spec();
+    if spec.channels == 0 || spec.sample_rate == 0 {
+      return Err(anyhow::anyhow!("invalid wav header in {}", path.display()));
+    }
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl AudioBackend for FileBackend {
+  async fn run(self: Box<Self>, tx: AudioSender) -> Result<(), anyhow::Error> {
+    task::spawn_blocking(move || {
+      playback_loop(self.config, self.path, self.looping, self.stop, tx)
+    })
+    .await??;
+    Ok(())
+  }
+}
+
+fn playback_loop(
+  config: AudioConfig,
+  path: PathBuf,
+  looping: bool,
+  stop: Arc<AtomicBool>,
+  mut tx: AudioSender,
+) -> Result<(), anyhow::Error> {
+  let mut reader = WavReader::open(&path)?;
+  let spec = reader.spec();
+  let sample_rate = spec.sample_rate;
+  let channels = spec.channels;
+  if channels == 0 || sample_rate == 0 {
+    return Err(anyhow::anyhow!("invalid wav header in {}", path.display()));
+  }
+
+  info!(
+    "playing {} ({} Hz, {} channels, {}-bit {:?})...",
+    path.display(),
+    sample_rate,
+    channels,
+    spec.bits_per_sample,
+    spec.sample_format
+  );
+
+  // one packet holds one fft worth of frames
+  let frames_per_packet = config.fft_size.max(1);
+  let packet_len = frames_per_packet * channels as usize;
+
+  let mut packet = AudioPacket::new(sample_rate as f32, channels, packet_len);
+  let mut pacer = Pacer::new(frames_per_packet, sample_rate);
+
+  // whether a frame has gone out since the last rewind, a file without any
+  // would otherwise loop on the spot
+  let mut played = false;
+
+  while !stop.load(Ordering::Relaxed) {
+    packet.samples.clear();
+    read_samples(&mut reader, packet_len, &mut packet.samples)?;
+
+    // a short read at the end of the file still needs whole frames
+    let whole = packet.samples.len() - packet.samples.len() % channels as usize;
+    packet.samples.truncate(whole);
+
+    if packet.samples.is_empty() {
+      if looping {
+        if !played {
+          return Err(anyhow::anyhow!(
+            "{} has no whole frames to loop",
+            path.display()
+          ));
+        }
+        // rewind and carry on without resetting the pace
+        reader.seek(0)?;
+        played = false;
+        packet.discontinuity = true;
+        continue;
+      }
+      break;
+    }
+    played = true;
+
+    packet.is_silent = packet.samples.iter().all(|&s| s == 0.0);
+
+    // hold the packet back until its start time has arrived
+    packet.timestamp = pacer.wait();
+
+    tx.write(&mut packet);
+  }
+
+  info!("file playback stopped...");
+  Ok(())
+}
+
+/// Read up to `count` samples, normalised to [-1, 1], into `out`
+fn read_samples(
+  reader: &mut WavReader<BufReader<File>>,
+  count: usize,
+  out: &mut Vec<f32>,
+) -> Result<(), anyhow::Error> {
+  let spec = reader.spec();
+  match spec.sample_format {
+    SampleFormat::Float => {
+      for s in reader.samples::<f32>().take(count) {
+        out.push(s?);
+      }
+    }
+    SampleFormat::Int => {
+      // hound widens 8/16/24/32-bit ints into i32 without rescaling
+      let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+      for s in reader.samples::<i32>().take(count) {
+        out.push(s? as f32 * scale);
+      }
+    }
+  }
+  Ok(())
+}
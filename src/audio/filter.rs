@@ -0,0 +1,388 @@
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+// butterworth, no peaking at the corner
+const DEFAULT_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+// narrow enough to take out mains hum and little else
+const DEFAULT_NOTCH_Q: f64 = 10.0;
+const DEFAULT_PREEMPHASIS: f64 = 0.95;
+
+// pole frequencies of the iec 61672 a and c weighting curves
+const WEIGHTING_F1: f64 = 20.598997;
+const WEIGHTING_F2: f64 = 107.65265;
+const WEIGHTING_F3: f64 = 737.86223;
+const WEIGHTING_F4: f64 = 12194.217;
+
+/// A time-domain stage run over the signal before it is windowed, carrying
+/// its state from one call to the next so a stream can be fed in pieces
+pub trait Filter: Send {
+  /// Filter `samples` in place
+  fn process(&mut self, samples: &mut [f32]);
+
+  /// Forget the signal so far, as if it had been silent
+  fn reset(&mut self);
+}
+
+/// One filter stage as given in the config, built once the rate is known
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterSpec {
+  HighPass {
+    freq: f64,
+    q: f64,
+  },
+  LowPass {
+    freq: f64,
+    q: f64,
+  },
+  Notch {
+    freq: f64,
+    q: f64,
+  },
+  /// First difference, `coef` being how much of the last sample is taken off
+  PreEmphasis {
+    coef: f64,
+  },
+  AWeighting,
+  CWeighting,
+}
+
+impl FromStr for FilterSpec {
+  type Err = anyhow::Error;
+
+  /// Parses `highpass:HZ[:Q]`, `lowpass:HZ[:Q]`, `notch:HZ[:Q]`,
+  /// `preemphasis[:COEF]`, `a-weighting` and `c-weighting`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parts = s.split(':');
+    let kind = parts.next().unwrap_or_default().to_ascii_lowercase();
+    let mut number = |default: Option<f64>| match parts.next() {
+      Some(v) => v
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite() && *v > 0.0)
+        .ok_or_else(|| anyhow::anyhow!("invalid number '{}' in filter '{}'", v, s)),
+      None => default.ok_or_else(|| anyhow::anyhow!("filter '{}' needs a frequency", s)),
+    };
+
+    let spec = match kind.as_str() {
+      "highpass" | "hp" => FilterSpec::HighPass {
+        freq: number(None)?,
+        q: number(Some(DEFAULT_Q))?,
+      },
+      "lowpass" | "lp" => FilterSpec::LowPass {
+        freq: number(None)?,
+        q: number(Some(DEFAULT_Q))?,
+      },
+      "notch" => FilterSpec::Notch {
+        freq: number(None)?,
+        q: number(Some(DEFAULT_NOTCH_Q))?,
+      },
+      "preemphasis" => FilterSpec::PreEmphasis {
+        coef: number(Some(DEFAULT_PREEMPHASIS))?.min(1.0),
+      },
+      "a-weighting" | "a" => FilterSpec::AWeighting,
+      "c-weighting" | "c" => FilterSpec::CWeighting,
+      _ => {
+        return Err(anyhow::anyhow!(
+          "unknown filter '{}', expected highpass, lowpass, notch, preemphasis, a-weighting or c-weighting",
+          s
+        ));
+      }
+    };
+    if parts.next().is_some() {
+      return Err(anyhow::anyhow!("too many parameters in filter '{}'", s));
+    }
+    Ok(spec)
+  }
+}
+
+impl FilterSpec {
+  pub fn build(&self, sample_rate: f32) -> Box<dyn Filter> {
+    let rate = sample_rate as f64;
+    // corners past nyquist would blow up, keep them just under it
+    let corner = |freq: f64| freq.min(rate * 0.49);
+    match *self {
+      FilterSpec::HighPass { freq, q } => Box::new(Biquad::high_pass(corner(freq), q, rate)),
+      FilterSpec::LowPass { freq, q } => Box::new(Biquad::low_pass(corner(freq), q, rate)),
+      FilterSpec::Notch { freq, q } => Box::new(Biquad::notch(corner(freq), q, rate)),
+      FilterSpec::PreEmphasis { coef } => Box::new(Biquad::new([1.0, -coef, 0.0], [1.0, 0.0, 0.0])),
+      FilterSpec::AWeighting => Box::new(weighting(
+        vec![
+          double_pole([1.0, 0.0, 0.0], WEIGHTING_F1, rate),
+          Biquad::from_analog(
+            [1.0, 0.0, 0.0],
+            [warp(WEIGHTING_F2, rate), warp(WEIGHTING_F3, rate)],
+            rate,
+          ),
+          Biquad::matched_low_pass(WEIGHTING_F4, 0.5, rate),
+        ],
+        rate,
+      )),
+      FilterSpec::CWeighting => Box::new(weighting(
+        vec![
+          double_pole([1.0, 0.0, 0.0], WEIGHTING_F1, rate),
+          Biquad::matched_low_pass(WEIGHTING_F4, 0.5, rate),
+        ],
+        rate,
+      )),
+    }
+  }
+}
+
+/// Stages run one after another, itself a filter
+#[derive(Default)]
+pub struct FilterChain {
+  filters: Vec<Box<dyn Filter>>,
+}
+
+impl FilterChain {
+  pub fn new(specs: &[FilterSpec], sample_rate: f32) -> Self {
+    Self {
+      filters: specs.iter().map(|spec| spec.build(sample_rate)).collect(),
+    }
+  }
+}
+
+impl Filter for FilterChain {
+  fn process(&mut self, samples: &mut [f32]) {
+    for filter in &mut self.filters {
+      filter.process(samples);
+    }
+  }
+
+  fn reset(&mut self) {
+    for filter in &mut self.filters {
+      filter.reset();
+    }
+  }
+}
+
+/// Second order iir section, transposed direct form II in double precision so
+/// corners far below the rate stay stable
+pub struct Biquad {
+  // b0, b1, b2 over a0
+  b: [f64; 3],
+  // a1, a2 over a0
+  a: [f64; 2],
+  state: [f64; 2],
+}
+
+impl Biquad {
+  /// From raw coefficients, `a[0]` being normalised away
+  pub fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+    Self {
+      b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+      a: [a[1] / a[0], a[2] / a[0]],
+      state: [0.0; 2],
+    }
+  }
+
+  /// From the audio eq cookbook
+  pub fn high_pass(freq: f64, q: f64, rate: f64) -> Self {
+    let (cos, alpha) = cookbook(freq, q, rate);
+    Self::new(
+      [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+      [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+    )
+  }
+
+  pub fn low_pass(freq: f64, q: f64, rate: f64) -> Self {
+    let (cos, alpha) = cookbook(freq, q, rate);
+    Self::new(
+      [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+      [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+    )
+  }
+
+  pub fn notch(freq: f64, q: f64, rate: f64) -> Self {
+    let (cos, alpha) = cookbook(freq, q, rate);
+    Self::new(
+      [1.0, -2.0 * cos, 1.0],
+      [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+    )
+  }
+
+  /// Low pass matching the analog one's magnitude all the way up to nyquist,
+  /// where the bilinear transform would squash it, following vicanek's
+  /// matched second order filters
+  fn matched_low_pass(freq: f64, q: f64, rate: f64) -> Self {
+    let w0 = 2.0 * PI * freq.min(rate * 0.49) / rate;
+    // poles from the impulse invariant transform
+    let zeta = 1.0 / (2.0 * q);
+    let a1 = if zeta <= 1.0 {
+      -2.0 * (-zeta * w0).exp() * (w0 * (1.0 - zeta * zeta).sqrt()).cos()
+    } else {
+      -2.0 * (-zeta * w0).exp() * (w0 * (zeta * zeta - 1.0).sqrt()).cosh()
+    };
+    let a2 = (-2.0 * zeta * w0).exp();
+    // zeros fitting the magnitude at dc and at the corner
+    let big_a0 = (1.0 + a1 + a2).powi(2);
+    let big_a1 = (1.0 - a1 + a2).powi(2);
+    let big_a2 = -4.0 * a2;
+    let phi1 = (w0 / 2.0).sin().powi(2);
+    let phi0 = 1.0 - phi1;
+    let phi2 = 4.0 * phi0 * phi1;
+    let r1 = (big_a0 * phi0 + big_a1 * phi1 + big_a2 * phi2) * q * q;
+    let b1_squared = ((r1 - big_a0 * phi0) / phi1).max(0.0);
+    let b0 = 0.5 * (big_a0.sqrt() + b1_squared.sqrt());
+    Self::new([b0, big_a0.sqrt() - b0, 0.0], [1.0, a1, a2])
+  }
+
+  /// Bilinear transform of `(n0 s^2 + n1 s + n2) / ((s + p0)(s + p1))`, poles
+  /// in rad/s
+  fn from_analog(num: [f64; 3], poles: [f64; 2], rate: f64) -> Self {
+    let k = 2.0 * rate;
+    let [n0, n1, n2] = num;
+    let [p0, p1] = poles;
+    let (d0, d1, d2) = (1.0, p0 + p1, p0 * p1);
+    Self::new(
+      [
+        n0 * k * k + n1 * k + n2,
+        2.0 * (n2 - n0 * k * k),
+        n0 * k * k - n1 * k + n2,
+      ],
+      [
+        d0 * k * k + d1 * k + d2,
+        2.0 * (d2 - d0 * k * k),
+        d0 * k * k - d1 * k + d2,
+      ],
+    )
+  }
+
+  /// Gain at `freq`
+  fn response(&self, freq: f64, rate: f64) -> f64 {
+    let w = 2.0 * PI * freq / rate;
+    let eval = |c: [f64; 3]| {
+      let re = c[0] + c[1] * w.cos() + c[2] * (2.0 * w).cos();
+      let im = -c[1] * w.sin() - c[2] * (2.0 * w).sin();
+      (re * re + im * im).sqrt()
+    };
+    eval(self.b) / eval([1.0, self.a[0], self.a[1]])
+  }
+
+  fn scale(&mut self, gain: f64) {
+    for b in &mut self.b {
+      *b *= gain;
+    }
+  }
+}
+
+impl Filter for Biquad {
+  fn process(&mut self, samples: &mut [f32]) {
+    let [b0, b1, b2] = self.b;
+    let [a1, a2] = self.a;
+    let [mut s1, mut s2] = self.state;
+    for sample in samples {
+      let x = *sample as f64;
+      let y = b0 * x + s1;
+      s1 = b1 * x - a1 * y + s2;
+      s2 = b2 * x - a2 * y;
+      *sample = y as f32;
+    }
+    self.state = [s1, s2];
+  }
+
+  fn reset(&mut self) {
+    self.state = [0.0; 2];
+  }
+}
+
+fn cookbook(freq: f64, q: f64, rate: f64) -> (f64, f64) {
+  let w = 2.0 * PI * freq / rate;
+  (w.cos(), w.sin() / (2.0 * q))
+}
+
+/// Pole at `freq` in rad/s, prewarped so it lands in the right place after
+/// the bilinear transform
+fn warp(freq: f64, rate: f64) -> f64 {
+  let freq = freq.min(rate * 0.49);
+  2.0 * rate * (PI * freq / rate).tan()
+}
+
+/// Section with a double pole at `freq` over `num`, a polynomial in `s`
+fn double_pole(num: [f64; 3], freq: f64, rate: f64) -> Biquad {
+  let pole = warp(freq, rate);
+  Biquad::from_analog(num, [pole, pole], rate)
+}
+
+/// Sections normalised to unity gain at 1kHz, as the curves are defined
+fn weighting(mut sections: Vec<Biquad>, rate: f64) -> FilterChain {
+  let gain: f64 = sections
+    .iter()
+    .map(|section| section.response(1000.0, rate))
+    .product();
+  if let Some(first) = sections.first_mut() {
+    first.scale(1.0 / gain);
+  }
+  FilterChain {
+    filters: sections
+      .into_iter()
+      .map(|section| Box::new(section) as Box<dyn Filter>)
+      .collect(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const RATE: f32 = 48_000.0;
+
+  /// Steady state gain of `spec` at `freq` in db, from a sine run through it
+  fn gain_db(spec: &str, freq: f64) -> f64 {
+    let mut filter = spec.parse::<FilterSpec>().unwrap().build(RATE);
+    let input: Vec<f32> = (0..RATE as usize)
+      .map(|n| (2.0 * PI * freq * n as f64 / RATE as f64).sin() as f32 * 0.5)
+      .collect();
+    let mut output = input.clone();
+    filter.process(&mut output);
+    // past the filter settling
+    let rms =
+      |s: &[f32]| (s.iter().map(|&x| (x as f64).powi(2)).sum::<f64>() / s.len() as f64).sqrt();
+    let half = input.len() / 2;
+    20.0 * (rms(&output[half..]) / rms(&input[half..])).log10()
+  }
+
+  fn assert_near(spec: &str, freq: f64, expected: f64, tolerance: f64) {
+    let db = gain_db(spec, freq);
+    assert!(
+      (db - expected).abs() <= tolerance,
+      "{} at {} Hz is {:.2} db, expected {:.1}",
+      spec,
+      freq,
+      db,
+      expected
+    );
+  }
+
+  #[test]
+  fn weightings_follow_the_iec_table() {
+    for (freq, a, c) in [
+      (1000.0, 0.0, 0.0),
+      (100.0, -19.1, -0.3),
+      (10_000.0, -2.5, -4.4),
+    ] {
+      assert_near("a-weighting", freq, a, 0.5);
+      assert_near("c-weighting", freq, c, 0.5);
+    }
+  }
+
+  #[test]
+  fn high_pass_cuts_well_below_its_corner() {
+    assert!(gain_db("highpass:100", 10.0) < -35.0);
+    assert_near("highpass:100", 100.0, -3.0, 0.1);
+    assert_near("highpass:100", 1000.0, 0.0, 0.1);
+  }
+
+  #[test]
+  fn notch_takes_out_its_centre() {
+    assert!(gain_db("notch:50", 50.0) < -40.0);
+    assert_near("notch:50", 1000.0, 0.0, 0.1);
+  }
+
+  #[test]
+  fn pre_emphasis_tilts_towards_the_treble() {
+    let tilt = gain_db("preemphasis", 5000.0) - gain_db("preemphasis", 100.0);
+    assert!(tilt > 15.0, "{} db", tilt);
+  }
+}
@@ -0,0 +1,236 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// How a single sample is stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleType {
+  I16,
+  /// Packed into 3 bytes
+  I24,
+  /// In the low 3 bytes of 4, the top one ignored
+  I24In32,
+  I32,
+  F32,
+  F64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endian {
+  Little,
+  Big,
+}
+
+/// Encoding of the samples in a raw interleaved buffer, shared by backends
+/// that get bytes rather than floats from their source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleFormat {
+  pub sample_type: SampleType,
+  pub endian: Endian,
+}
+
+impl SampleFormat {
+  pub const fn new(sample_type: SampleType, endian: Endian) -> Self {
+    Self {
+      sample_type,
+      endian,
+    }
+  }
+
+  pub const fn bytes_per_sample(self) -> usize {
+    match self.sample_type {
+      SampleType::I16 => 2,
+      SampleType::I24 => 3,
+      SampleType::I24In32 | SampleType::I32 | SampleType::F32 => 4,
+      SampleType::F64 => 8,
+    }
+  }
+
+  /// Decode one sample from the front of `bytes`, integers normalised to
+  /// [-1.0, 1.0)
+  pub fn decode(self, bytes: &[u8]) -> f32 {
+    match self.sample_type {
+      SampleType::I16 => i16::from_le_bytes(self.ordered(bytes)) as f32 / 32_768.0,
+      SampleType::I24 => {
+        let [a, b, c] = self.ordered(bytes);
+        // shift into the top of an i32 so the sign bit lands in place
+        let v = i32::from_le_bytes([0, a, b, c]) >> 8;
+        v as f32 / 8_388_608.0
+      }
+      SampleType::I24In32 => {
+        let [a, b, c, _] = self.ordered(bytes);
+        let v = i32::from_le_bytes([0, a, b, c]) >> 8;
+        v as f32 / 8_388_608.0
+      }
+      SampleType::I32 => i32::from_le_bytes(self.ordered(bytes)) as f32 / 2_147_483_648.0,
+      SampleType::F32 => f32::from_le_bytes(self.ordered(bytes)),
+      SampleType::F64 => f64::from_le_bytes(self.ordered(bytes)) as f32,
+    }
+  }
+
+  /// Decode every whole sample in `bytes` onto the end of `out`
+  pub fn decode_into(self, bytes: &[u8], out: &mut Vec<f32>) {
+    out.extend(
+      bytes
+        .chunks_exact(self.bytes_per_sample())
+        .map(|b| self.decode(b)),
+    );
+  }
+
+  /// First `N` bytes in little-endian order
+  fn ordered<const N: usize>(self, bytes: &[u8]) -> [u8; N] {
+    let mut out = [0u8; N];
+    out.copy_from_slice(&bytes[..N]);
+    if self.endian == Endian::Big {
+      out.reverse();
+    }
+    out
+  }
+}
+
+impl fmt::Display for SampleFormat {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let sample_type = match self.sample_type {
+      SampleType::I16 => "s16",
+      SampleType::I24 => "s24",
+      SampleType::I24In32 => "s24_32",
+      SampleType::I32 => "s32",
+      SampleType::F32 => "f32",
+      SampleType::F64 => "f64",
+    };
+    let endian = match self.endian {
+      Endian::Little => "le",
+      Endian::Big => "be",
+    };
+    write!(f, "{}{}", sample_type, endian)
+  }
+}
+
+impl FromStr for SampleFormat {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    // names follow ffmpeg/sox conventions, little-endian unless told otherwise
+    let s = s.to_ascii_lowercase();
+    let (name, endian) = if let Some(name) = s.strip_suffix("be") {
+      (name, Endian::Big)
+    } else {
+      (s.strip_suffix("le").unwrap_or(&s), Endian::Little)
+    };
+    let sample_type = match name {
+      "s16" => SampleType::I16,
+      "s24" => SampleType::I24,
+      "s24_32" => SampleType::I24In32,
+      "s32" => SampleType::I32,
+      "f32" | "float32" => SampleType::F32,
+      "f64" | "float64" => SampleType::F64,
+      _ => {
+        return Err(anyhow::anyhow!(
+          "unknown sample format '{}', expected s16, s24, s24_32, s32, f32 or f64 with an optional le/be suffix",
+          s
+        ));
+      }
+    };
+    Ok(Self::new(sample_type, endian))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn decode(format: &str, bytes: &[u8]) -> Vec<f32> {
+    let mut out = Vec::new();
+    format
+      .parse::<SampleFormat>()
+      .unwrap()
+      .decode_into(bytes, &mut out);
+    out
+  }
+
+  #[test]
+  fn integers_decode_full_scale() {
+    assert_eq!(
+      decode("s16le", &[0xff, 0x7f, 0x00, 0x80, 0x00, 0x00]),
+      [32_767.0 / 32_768.0, -1.0, 0.0]
+    );
+    assert_eq!(
+      decode("s16be", &[0x7f, 0xff, 0x80, 0x00, 0x00, 0x00]),
+      [32_767.0 / 32_768.0, -1.0, 0.0]
+    );
+    assert_eq!(
+      decode(
+        "s24le",
+        &[0xff, 0xff, 0x7f, 0x00, 0x00, 0x80, 0xff, 0xff, 0xff]
+      ),
+      [8_388_607.0 / 8_388_608.0, -1.0, -1.0 / 8_388_608.0]
+    );
+    assert_eq!(
+      decode(
+        "s24be",
+        &[0x7f, 0xff, 0xff, 0x80, 0x00, 0x00, 0xff, 0xff, 0xff]
+      ),
+      [8_388_607.0 / 8_388_608.0, -1.0, -1.0 / 8_388_608.0]
+    );
+    // whatever is in the padding byte
+    assert_eq!(
+      decode(
+        "s24_32le",
+        &[
+          0xff, 0xff, 0x7f, 0x00, 0x00, 0x00, 0x80, 0xff, 0xff, 0xff, 0xff, 0x00
+        ]
+      ),
+      [8_388_607.0 / 8_388_608.0, -1.0, -1.0 / 8_388_608.0]
+    );
+    assert_eq!(
+      decode(
+        "s24_32be",
+        &[
+          0x00, 0x7f, 0xff, 0xff, 0xff, 0x80, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff
+        ]
+      ),
+      [8_388_607.0 / 8_388_608.0, -1.0, -1.0 / 8_388_608.0]
+    );
+    assert_eq!(
+      decode("s32le", &[0xff, 0xff, 0xff, 0x7f, 0x00, 0x00, 0x00, 0x80]),
+      [2_147_483_647.0 / 2_147_483_648.0, -1.0]
+    );
+    assert_eq!(
+      decode("s32be", &[0x7f, 0xff, 0xff, 0xff, 0x80, 0x00, 0x00, 0x00]),
+      [2_147_483_647.0 / 2_147_483_648.0, -1.0]
+    );
+  }
+
+  #[test]
+  fn floats_decode_as_is() {
+    let samples = [1.0f32, -1.0, 0.25];
+    let le: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    let be: Vec<u8> = samples.iter().flat_map(|s| s.to_be_bytes()).collect();
+    assert_eq!(decode("f32le", &le), samples);
+    assert_eq!(decode("f32be", &be), samples);
+
+    let samples = [1.0f64, -1.0, -0.5];
+    let le: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    let be: Vec<u8> = samples.iter().flat_map(|s| s.to_be_bytes()).collect();
+    assert_eq!(decode("f64le", &le), [1.0, -1.0, -0.5]);
+    assert_eq!(decode("f64be", &be), [1.0, -1.0, -0.5]);
+  }
+
+  #[test]
+  fn partial_sample_is_left_off() {
+    assert_eq!(decode("s16le", &[0x00, 0x40, 0x00]), [0.5]);
+    assert_eq!(decode("s24be", &[0x40, 0x00, 0x00, 0x40, 0x00]), [0.5]);
+    assert_eq!(decode("f32le", &[0, 0, 0x80, 0x3f, 0, 0]), [1.0]);
+  }
+
+  #[test]
+  fn names_round_trip() {
+    for name in ["s16le", "s24be", "s24_32le", "s32le", "f32be", "f64le"] {
+      assert_eq!(name.parse::<SampleFormat>().unwrap().to_string(), name);
+    }
+    assert_eq!(
+      "float32".parse::<SampleFormat>().unwrap(),
+      SampleFormat::new(SampleType::F32, Endian::Little)
+    );
+    assert!("u8".parse::<SampleFormat>().is_err());
+  }
+}
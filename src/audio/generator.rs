@@ -0,0 +1,326 @@
+use std::f64::consts::TAU;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rand::Rng;
+use rand::rngs::ThreadRng;
+
+use async_trait::async_trait;
+
+use tokio::task;
+
+use tracing::info;
+
+use crate::audio::AudioConfig;
+use crate::audio::backend::{AudioBackend, AudioPacket, AudioSender, Pacer};
+
+/// Test signals the generator can produce
+#[derive(Clone, Debug)]
+pub enum Signal {
+  Sine(f64),
+  MultiTone(Vec<f64>),
+  /// Exponential sweep from `start` to `end` Hz, restarting every `seconds`
+  Sweep {
+    start: f64,
+    end: f64,
+    seconds: f64,
+  },
+  WhiteNoise,
+  PinkNoise,
+  /// Single-sample clicks at the given rate in Hz
+  Impulse(f64),
+  Silence,
+}
+
+impl FromStr for Signal {
+  type Err = anyhow::Error;
+
+  /// Parses `sine:1000`, `tones:100,1000,5000`, `sweep:20-20000:10`, `white`,
+  /// `pink`, `impulse:2` and `silence`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (kind, params) = s.split_once(':').unwrap_or((s, ""));
+    let number = |v: &str| {
+      v.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite() && *v > 0.0)
+        .ok_or_else(|| anyhow::anyhow!("invalid number '{}' in signal '{}'", v, s))
+    };
+
+    let signal = match kind.to_ascii_lowercase().as_str() {
+      "sine" => Signal::Sine(number(params)?),
+      "tones" => Signal::MultiTone(params.split(',').map(number).collect::<Result<_, _>>()?),
+      "sweep" => {
+        let (range, seconds) = params.split_once(':').unwrap_or((params, "10"));
+        let (start, end) = range
+          .split_once('-')
+          .ok_or_else(|| anyhow::anyhow!("sweep needs a range like 20-20000"))?;
+        Signal::Sweep {
+          start: number(start)?,
+          end: number(end)?,
+          seconds: number(seconds)?,
+        }
+      }
+      "white" => Signal::WhiteNoise,
+      "pink" => Signal::PinkNoise,
+      "impulse" => Signal::Impulse(number(params)?),
+      "silence" => Signal::Silence,
+      _ => {
+        return Err(anyhow::anyhow!(
+          "unknown signal '{}', expected sine, tones, sweep, white, pink, impulse or silence",
+          kind
+        ));
+      }
+    };
+    Ok(signal)
+  }
+}
+
+/// Synthesises a known signal at real-time pace, copied to every channel.
+pub struct SignalGeneratorBackend {
+  config: AudioConfig,
+  signal: Signal,
+  amplitude: f32,
+  sample_rate: u32,
+  channels: u16,
+  stop: Arc<AtomicBool>,
+}
+
+impl SignalGeneratorBackend {
+  pub fn new(
+    config: AudioConfig,
+    signal: Signal,
+    amplitude: f32,
+    sample_rate: u32,
+    channels: u16,
+    stop: Arc<AtomicBool>,
+  ) -> Self {
+    Self {
+      config,
+      signal,
+      amplitude,
+      sample_rate,
+      channels,
+      stop,
+    }
+  }
+}
+
+#[async_trait]
+impl AudioBackend for SignalGeneratorBackend {
+  async fn run(self: Box<Self>, tx: AudioSender) -> Result<(), anyhow::Error> {
+    task::spawn_blocking(move || generate_loop(*self, tx)).await??;
+    Ok(())
+  }
+}
+
+fn generate_loop(
+  backend: SignalGeneratorBackend,
+  mut tx: AudioSender,
+) -> Result<(), anyhow::Error> {
+  if backend.channels == 0 || backend.sample_rate == 0 {
+    return Err(anyhow::anyhow!(
+      "generator needs a non-zero rate and channel count"
+    ));
+  }
+
+  info!(
+    "generating {:?} ({} Hz, {} channels)...",
+    backend.signal, backend.sample_rate, backend.channels
+  );
+
+  let channels = backend.channels as usize;
+  let frames_per_packet = backend.config.fft_size.max(1);
+  let mut oscillator = Oscillator::new(backend.signal, backend.sample_rate as f64);
+  let mut packet = AudioPacket::new(
+    backend.sample_rate as f32,
+    backend.channels,
+    frames_per_packet * channels,
+  );
+  let mut pacer = Pacer::new(frames_per_packet, backend.sample_rate);
+
+  while !backend.stop.load(Ordering::Relaxed) {
+    packet.samples.clear();
+    for _ in 0..frames_per_packet {
+      let s = oscillator.next_sample() * backend.amplitude;
+      packet.samples.extend(std::iter::repeat_n(s, channels));
+    }
+    packet.is_silent = packet.samples.iter().all(|&s| s == 0.0);
+
+    packet.timestamp = pacer.wait();
+
+    tx.write(&mut packet);
+  }
+
+  info!("signal generator stopped...");
+  Ok(())
+}
+
+/// Per-sample state for a `Signal`
+struct Oscillator {
+  signal: Signal,
+  sample_rate: f64,
+  // one phase per tone, in radians
+  phases: Vec<f64>,
+  // samples since the signal (or current sweep) started
+  position: u64,
+  // paul kellet's pink noise filter state
+  pink: [f64; 7],
+  rng: ThreadRng,
+}
+
+impl Oscillator {
+  fn new(signal: Signal, sample_rate: f64) -> Self {
+    let tones = match &signal {
+      Signal::MultiTone(freqs) => freqs.len(),
+      _ => 1,
+    };
+    Self {
+      signal,
+      sample_rate,
+      phases: vec![0.0; tones],
+      position: 0,
+      pink: [0.0; 7],
+      rng: rand::rng(),
+    }
+  }
+
+  fn next_sample(&mut self) -> f32 {
+    let value = match &self.signal {
+      Signal::Sine(freq) => {
+        let v = self.phases[0].sin();
+        self.phases[0] = (self.phases[0] + TAU * freq / self.sample_rate) % TAU;
+        v
+      }
+      Signal::MultiTone(freqs) => {
+        let mut sum = 0.0;
+        for (phase, freq) in self.phases.iter_mut().zip(freqs) {
+          sum += phase.sin();
+          *phase = (*phase + TAU * freq / self.sample_rate) % TAU;
+        }
+        // keep the sum within full scale
+        sum / freqs.len().max(1) as f64
+      }
+      Signal::Sweep {
+        start,
+        end,
+        seconds,
+      } => {
+        let length = (seconds * self.sample_rate) as u64;
+        if self.position >= length {
+          self.position = 0;
+          self.phases[0] = 0.0;
+        }
+        let t = self.position as f64 / length.max(1) as f64;
+        let freq = start * (end / start).powf(t);
+        let v = self.phases[0].sin();
+        self.phases[0] = (self.phases[0] + TAU * freq / self.sample_rate) % TAU;
+        v
+      }
+      Signal::WhiteNoise => self.rng.random_range(-1.0..=1.0),
+      Signal::PinkNoise => {
+        let white: f64 = self.rng.random_range(-1.0..=1.0);
+        let b = &mut self.pink;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.1538520;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        // the filter has roughly 5x gain, bring it back under full scale
+        (pink * 0.2).clamp(-1.0, 1.0)
+      }
+      Signal::Impulse(rate) => {
+        let period = (self.sample_rate / rate).max(1.0) as u64;
+        if self.position.is_multiple_of(period) {
+          1.0
+        } else {
+          0.0
+        }
+      }
+      Signal::Silence => 0.0,
+    };
+    self.position += 1;
+    value as f32
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audio::processor::{AudioProcessor, Channel, Magnitude};
+  use crate::audio::test_config;
+
+  const RATE: f64 = 48_000.0;
+
+  /// A second of `signal` through a fresh processor, in the app's block size
+  fn analyse(config: AudioConfig, signal: Signal) -> AudioProcessor {
+    let mut oscillator = Oscillator::new(signal, RATE);
+    let samples: Vec<f32> = (0..RATE as usize)
+      .map(|_| oscillator.next_sample())
+      .collect();
+    let mut processor = AudioProcessor::new(config.clone());
+    for block in samples.chunks(config.hop_size) {
+      processor.process(block, 1, RATE as f32);
+    }
+    processor
+  }
+
+  #[test]
+  fn tone_lands_in_its_bar() {
+    let mut config = test_config();
+    // calibrated, so gain can't pin the bars either side of a lone tone
+    config.magnitude = Magnitude::Decibels {
+      floor: -90.0,
+      ceiling: 0.0,
+    };
+    let edges = config
+      .scale
+      .edges(config.bar_count, config.min_freq, config.max_freq);
+    for freq in [100.0, 1000.0, 5000.0] {
+      let processor = analyse(config.clone(), Signal::Sine(freq));
+      let bars = processor.spectrum(Channel::Mono);
+      let peak = (0..bars.len())
+        .max_by(|&a, &b| bars[a].total_cmp(&bars[b]))
+        .unwrap();
+      assert!(
+        edges[peak] <= freq as f32 && (freq as f32) < edges[peak + 1],
+        "{} Hz peaked in bar {} ({}..{} Hz)",
+        freq,
+        peak,
+        edges[peak],
+        edges[peak + 1]
+      );
+    }
+  }
+
+  #[test]
+  fn bars_decay_through_silence() {
+    let mut processor = analyse(test_config(), Signal::Sine(1000.0));
+    let level = |p: &AudioProcessor| {
+      p.spectrum(Channel::Mono)
+        .iter()
+        .copied()
+        .fold(0.0, f32::max)
+    };
+    let mut last = level(&processor);
+    assert!(last > 0.5, "tone only reached {}", last);
+
+    for _ in 0..1000 {
+      processor.process(&[], 1, RATE as f32);
+      let now = level(&processor);
+      assert!(now < last, "bars went from {} to {} in silence", last, now);
+      last = now;
+    }
+    assert!(last < 0.01, "bars still at {} after the silence", last);
+  }
+
+  #[test]
+  fn silence_stays_flat() {
+    let processor = analyse(test_config(), Signal::Silence);
+    assert!(processor.spectrum(Channel::Mono).iter().all(|&v| v == 0.0));
+  }
+}
@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use ::jack::{
+  AudioIn, Client, ClientOptions, ClientStatus, Control, NotificationHandler, Port, PortFlags,
+  ProcessHandler, ProcessScope,
+};
+
+use async_trait::async_trait;
+
+use tokio::task;
+
+use tracing::{error, info, warn};
+
+use crate::audio::backend::{AudioBackend, AudioPacket, AudioSender, DeviceInfo};
+
+/// Registers as a jack client with one input port per channel, optionally
+/// wiring each port to a named output port (e.g. `system:capture_1`), or to
+/// every output port of a named client.
+///
+/// Samples are interleaved and written to the sample ring from the process
+/// callback, so the realtime thread never takes a lock.
+pub struct JackBackend {
+  connect: Vec<String>,
+  // client whose outputs we connect to when no ports are named
+  device: Option<String>,
+  channels: u16,
+  stop: Arc<AtomicBool>,
+}
+
+impl JackBackend {
+  pub fn new(
+    connect: Vec<String>,
+    device: Option<String>,
+    channels: u16,
+    stop: Arc<AtomicBool>,
+  ) -> Self {
+    Self {
+      connect,
+      device,
+      channels,
+      stop,
+    }
+  }
+
+  /// Check that a jack server is running, without starting one
+  pub fn probe() -> Result<(), anyhow::Error> {
+    Client::new("field-probe", ClientOptions::NO_START_SERVER)?;
+    Ok(())
+  }
+
+  /// Every client with audio outputs, hardware capture as inputs and other
+  /// applications as loopback. The rate is the server's, shared by all
+  pub fn devices() -> Result<Vec<DeviceInfo>, anyhow::Error> {
+    let (client, _status) = Client::new("field-probe", ClientOptions::NO_START_SERVER)?;
+
+    // client name -> (output port count, physical)
+    let mut clients: BTreeMap<String, (u16, bool)> = BTreeMap::new();
+    for port in audio_outputs(&client) {
+      let Some((owner, _)) = port.split_once(':') else {
+        continue;
+      };
+      let physical = client
+        .port_by_name(&port)
+        .is_some_and(|p| p.flags().contains(PortFlags::IS_PHYSICAL));
+      let entry = clients.entry(owner.to_owned()).or_default();
+      entry.0 += 1;
+      entry.1 |= physical;
+    }
+
+    let devices = clients
+      .into_iter()
+      .map(|(id, (channels, physical))| DeviceInfo {
+        name: id.clone(),
+        loopback: !physical,
+        is_default: physical && id == "system",
+        id,
+        sample_rate: Some(client.sample_rate() as u32),
+        channels: Some(channels),
+      })
+      .collect();
+    Ok(devices)
+  }
+}
+
+#[async_trait]
+impl AudioBackend for JackBackend {
+  async fn run(self: Box<Self>, tx: AudioSender) -> Result<(), anyhow::Error> {
+    task::spawn_blocking(move || client_loop(*self, tx)).await??;
+    Ok(())
+  }
+}
+
+/// Names of every audio output port on the server
+fn audio_outputs(client: &Client) -> Vec<String> {
+  client.ports(None, Some("audio"), PortFlags::IS_OUTPUT)
+}
+
+fn client_loop(backend: JackBackend, tx: AudioSender) -> Result<(), anyhow::Error> {
+  let (client, _status) = Client::new("field", ClientOptions::NO_START_SERVER)?;
+
+  // named ports win, otherwise take every output of the chosen client
+  let connect = match &backend.device {
+    Some(device) if backend.connect.is_empty() => {
+      let prefix = format!("{}:", device);
+      let ports: Vec<_> = audio_outputs(&client)
+        .into_iter()
+        .filter(|port| port.starts_with(&prefix))
+        .collect();
+      if ports.is_empty() {
+        return Err(anyhow::anyhow!(
+          "jack client {} has no audio outputs",
+          device
+        ));
+      }
+      ports
+    }
+    _ => backend.connect.clone(),
+  };
+
+  // one port per channel, or per requested connection if there are more
+  let channels = (backend.channels as usize).max(connect.len()).max(1);
+  let ports = (0..channels)
+    .map(|i| client.register_port(&format!("in_{}", i + 1), AudioIn))
+    .collect::<Result<Vec<_>, _>>()?;
+  let port_names = ports
+    .iter()
+    .map(|p| p.name())
+    .collect::<Result<Vec<_>, _>>()?;
+
+  info!(
+    "jack client {} registered ({} Hz, {} frame buffers, {} ports)...",
+    client.name(),
+    client.sample_rate(),
+    client.buffer_size(),
+    channels
+  );
+
+  let alive = Arc::new(AtomicBool::new(true));
+  let xrun = Arc::new(AtomicBool::new(false));
+  let capture = Capture {
+    packet: AudioPacket::new(
+      client.sample_rate() as f32,
+      channels as u16,
+      client.buffer_size() as usize * channels,
+    ),
+    ports,
+    tx,
+    xrun: Arc::clone(&xrun),
+    next_frame_time: None,
+  };
+  let notifications = Notifications {
+    alive: Arc::clone(&alive),
+    xrun,
+  };
+  let active = client.activate_async(notifications, capture)?;
+
+  // connections can only be made once the client is active
+  for (source, port) in connect.iter().zip(&port_names) {
+    match active.as_client().connect_ports_by_name(source, port) {
+      Ok(()) => info!("connected {} -> {}", source, port),
+      Err(e) => warn!("could not connect {} -> {} - {}", source, port, e),
+    }
+  }
+
+  // the process callback does the work, we just wait to be told to stop
+  while !backend.stop.load(Ordering::Relaxed) && alive.load(Ordering::Relaxed) {
+    std::thread::sleep(Duration::from_millis(100));
+  }
+
+  if !alive.load(Ordering::Relaxed) {
+    return Err(anyhow::anyhow!("jack server shut down"));
+  }
+
+  active.deactivate()?;
+  info!("jack capture stopped...");
+  Ok(())
+}
+
+/// Realtime side, runs inside jack's process thread
+struct Capture {
+  ports: Vec<Port<AudioIn>>,
+  tx: AudioSender,
+  // reused every cycle, sized whenever jack's buffer size is set
+  packet: AudioPacket,
+  // raised by the notification thread, taken by the next cycle
+  xrun: Arc<AtomicBool>,
+  // server frame time the next cycle should start at if none were missed
+  next_frame_time: Option<u32>,
+}
+
+impl ProcessHandler for Capture {
+  fn process(&mut self, client: &Client, ps: &ProcessScope) -> Control {
+    let channels = self.ports.len();
+    let frames = ps.n_frames() as usize;
+    let packet = &mut self.packet;
+
+    for (ch, port) in self.ports.iter().enumerate() {
+      let dst = packet.samples[ch..].iter_mut().step_by(channels);
+      for (out, s) in dst.zip(port.as_slice(ps)) {
+        *out = *s;
+      }
+    }
+
+    // the frame clock wraps, so compare and step it with wrapping maths, a
+    // clock that went back leaving the position where it was
+    let frame_time = ps.last_frame_time();
+    if let Some(expected) = self.next_frame_time
+      && frame_time != expected
+    {
+      packet.discontinuity = true;
+      let skipped = frame_time.wrapping_sub(expected);
+      if (skipped as i32) > 0 {
+        packet.position += skipped as u64;
+      }
+    }
+    self.next_frame_time = Some(frame_time.wrapping_add(frames as u32));
+
+    packet.sample_rate = client.sample_rate() as f32;
+    packet.is_silent = packet.samples.iter().all(|&s| s == 0.0);
+    // this cycle's input was captured over the period before it
+    packet.timestamp = Instant::now() - packet.duration();
+    packet.glitch = self.xrun.swap(false, Ordering::Relaxed);
+    self.tx.write(packet);
+
+    Control::Continue
+  }
+
+  /// Called before the first cycle and whenever the size changes, outside
+  /// the realtime constraints, so the packet and sender are sized here and
+  /// the process callback never allocates
+  fn buffer_size(&mut self, client: &Client, size: ::jack::Frames) -> Control {
+    let channels = self.ports.len();
+    let frames = size as usize;
+    self.packet.samples.resize(frames * channels, 0.0);
+    self
+      .tx
+      .prepare(client.sample_rate() as f32, channels as u16, frames);
+    Control::Continue
+  }
+}
+
+struct Notifications {
+  alive: Arc<AtomicBool>,
+  xrun: Arc<AtomicBool>,
+}
+
+impl NotificationHandler for Notifications {
+  fn shutdown(&mut self, _status: ClientStatus, reason: &str) {
+    error!("jack server shut down - {}", reason);
+    self.alive.store(false, Ordering::Relaxed);
+  }
+
+  fn xrun(&mut self, _: &Client) -> Control {
+    self.xrun.store(true, Ordering::Relaxed);
+    Control::Continue
+  }
+}
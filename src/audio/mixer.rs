@@ -0,0 +1,627 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use tokio::task::JoinHandle;
+
+use tracing::{info, warn};
+
+use crate::audio::AudioConfig;
+use crate::audio::backend::{AudioBackend, AudioPacket, AudioSender};
+use crate::audio::ring::{RingReader, SampleRing};
+
+// how far behind real time the mix runs, so every source has caught up
+const MIX_DELAY: Duration = Duration::from_millis(60);
+// how often a block is mixed
+const MIX_INTERVAL: Duration = Duration::from_millis(10);
+// frames copied out of a source's ring at a time
+const CHUNK_FRAMES: usize = 32;
+// how far a source's timestamps can wander before it is realigned
+const DRIFT_TOLERANCE: Duration = Duration::from_millis(20);
+// rate sources are brought to when analysis runs at the device rate
+const DEFAULT_RATE: u32 = 48_000;
+
+/// One input of the mix, given as `NAME[@DEVICE][*GAIN]`, e.g. `pulse`,
+/// `pulse@alsa_input.usb-mic*0.5` or `generator*0.2`
+#[derive(Clone, Debug)]
+pub struct MixSource {
+  pub backend: String,
+  pub device: Option<String>,
+  pub gain: f32,
+}
+
+impl FromStr for MixSource {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (rest, gain) = match s.rsplit_once('*') {
+      Some((rest, gain)) => {
+        let gain = gain
+          .trim()
+          .parse::<f32>()
+          .ok()
+          .filter(|gain| gain.is_finite() && *gain >= 0.0)
+          .ok_or_else(|| anyhow::anyhow!("invalid gain '{}' in mix source '{}'", gain, s))?;
+        (rest, gain)
+      }
+      None => (s, 1.0),
+    };
+    let (backend, device) = match rest.split_once('@') {
+      Some((backend, device)) => (backend, Some(device.to_string())),
+      None => (rest, None),
+    };
+    if backend.is_empty() {
+      return Err(anyhow::anyhow!("mix source '{}' names no backend", s));
+    }
+    Ok(Self {
+      backend: backend.to_string(),
+      device,
+      gain,
+    })
+  }
+}
+
+/// How sources are combined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MixMode {
+  /// Summed with their gains, over as many channels as the widest source
+  Sum,
+  /// Side by side, each source keeping its own channels
+  Split,
+}
+
+impl FromStr for MixMode {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_ascii_lowercase().as_str() {
+      "sum" => Ok(MixMode::Sum),
+      "split" => Ok(MixMode::Split),
+      _ => Err(anyhow::anyhow!(
+        "unknown mix mode '{}', expected sum or split",
+        s
+      )),
+    }
+  }
+}
+
+/// Runs several backends at once and combines them into one stream, e.g.
+/// loopback of the music plus a microphone.
+///
+/// Every source writes into a ring of its own through an `AudioSender`, which
+/// brings it to a common rate. The mix then runs a little behind real time,
+/// lining sources up by their capture timestamps and filling in silence for
+/// any that haven't caught up. It ends once every source has, and fails as
+/// soon as any one of them does.
+pub struct MixerBackend {
+  sources: Vec<(MixSource, Box<dyn AudioBackend>)>,
+  mode: MixMode,
+  sample_rate: u32,
+  history_size: usize,
+  stop: Arc<AtomicBool>,
+  // stops the sources without stopping the app
+  sources_stop: Arc<AtomicBool>,
+}
+
+impl MixerBackend {
+  pub fn new(
+    config: AudioConfig,
+    sources: Vec<(MixSource, Box<dyn AudioBackend>)>,
+    mode: MixMode,
+    stop: Arc<AtomicBool>,
+    sources_stop: Arc<AtomicBool>,
+  ) -> Self {
+    Self {
+      sources,
+      mode,
+      sample_rate: config.analysis_rate.unwrap_or(DEFAULT_RATE),
+      history_size: config.history_size,
+      stop,
+      sources_stop,
+    }
+  }
+}
+
+#[async_trait]
+impl AudioBackend for MixerBackend {
+  async fn run(self: Box<Self>, tx: AudioSender) -> Result<(), anyhow::Error> {
+    let MixerBackend {
+      sources,
+      mode,
+      sample_rate,
+      history_size,
+      stop,
+      sources_stop,
+    } = *self;
+
+    let mut lanes = Vec::with_capacity(sources.len());
+    for (source, backend) in sources {
+      let ring = SampleRing::new(history_size);
+      let source_tx = AudioSender::new(ring.writer(), Some(sample_rate));
+      info!("mixing {} at {:.2}x gain...", source.backend, source.gain);
+      lanes.push(Lane {
+        reader: ring.reader(),
+        handle: Some(tokio::spawn(backend.run(source_tx))),
+        source,
+        queue: VecDeque::new(),
+        head_time: None,
+        channels: 0,
+        chunk: Vec::new(),
+      });
+    }
+
+    let result = mix_loop(&mut lanes, mode, sample_rate, &stop, tx).await;
+
+    // take the sources down with us, whichever way we went
+    sources_stop.store(true, Ordering::Relaxed);
+    for lane in &mut lanes {
+      if let Some(handle) = lane.handle.take() {
+        let _ = handle.await;
+      }
+    }
+    result
+  }
+}
+
+async fn mix_loop(
+  lanes: &mut [Lane],
+  mode: MixMode,
+  sample_rate: u32,
+  stop: &AtomicBool,
+  mut tx: AudioSender,
+) -> Result<(), anyhow::Error> {
+  let rate = sample_rate as f64;
+  let block_frames = (rate * MIX_INTERVAL.as_secs_f64()).round() as usize;
+  let block = Duration::from_secs_f64(block_frames as f64 / rate);
+  let mut packet = AudioPacket::new(sample_rate as f32, 0, 0);
+  let mut lane_out = Vec::new();
+  // start of the next block to mix
+  let mut next = Instant::now() - MIX_DELAY;
+  let mut interval = tokio::time::interval(MIX_INTERVAL);
+
+  while !stop.load(Ordering::Relaxed) {
+    interval.tick().await;
+
+    let mut running = 0;
+    for lane in lanes.iter_mut() {
+      if lane.poll().await? {
+        running += 1;
+      }
+      lane.pull(rate);
+    }
+    if running == 0 && lanes.iter().all(|lane| lane.queue.is_empty()) {
+      info!("every mixed source has finished...");
+      return Ok(());
+    }
+
+    // mix whatever is due, catching up in one go if we were held up
+    let now = Instant::now();
+    if now.saturating_duration_since(next) > MIX_DELAY * 4 {
+      next = now - MIX_DELAY;
+      packet.discontinuity = true;
+    }
+    while next + block + MIX_DELAY <= now {
+      mix_block(
+        lanes,
+        mode,
+        next,
+        block_frames,
+        rate,
+        &mut lane_out,
+        &mut packet,
+      );
+      tx.write(&mut packet);
+      next += block;
+    }
+  }
+  Ok(())
+}
+
+/// Mix the `frames` frames from `start` into `packet`, `lane_out` holding
+/// each lane's share on the way
+fn mix_block(
+  lanes: &mut [Lane],
+  mode: MixMode,
+  start: Instant,
+  frames: usize,
+  rate: f64,
+  lane_out: &mut Vec<f32>,
+  packet: &mut AudioPacket,
+) {
+  let channels = match mode {
+    MixMode::Sum => lanes.iter().map(|lane| lane.channels).max().unwrap_or(0),
+    MixMode::Split => lanes.iter().map(|lane| lane.channels).sum(),
+  }
+  .max(1);
+  packet.channels = channels;
+  packet.samples.clear();
+  packet.samples.resize(frames * channels as usize, 0.0);
+
+  let mut offset = 0;
+  for lane in lanes.iter_mut() {
+    let lane_channels = lane.channels.max(1) as usize;
+    lane.take(start, frames, rate, lane_out);
+    let gain = lane.source.gain;
+    for (frame, out) in lane_out
+      .chunks_exact(lane_channels)
+      .zip(packet.samples.chunks_exact_mut(channels as usize))
+    {
+      match mode {
+        // narrower sources repeat across the wider layout
+        MixMode::Sum => {
+          for (ch, s) in out.iter_mut().enumerate() {
+            *s += frame[ch % lane_channels] * gain;
+          }
+        }
+        MixMode::Split => {
+          for (s, v) in out[offset..].iter_mut().zip(frame) {
+            *s += v * gain;
+          }
+        }
+      }
+    }
+    offset += lane.channels as usize;
+  }
+
+  packet.is_silent = packet.samples.iter().all(|&s| s == 0.0);
+  packet.timestamp = start;
+}
+
+/// A source's task and what it has captured so far
+struct Lane {
+  source: MixSource,
+  reader: RingReader,
+  // none once the source has finished
+  handle: Option<JoinHandle<Result<(), anyhow::Error>>>,
+  // interleaved frames at the mix rate, not yet mixed
+  queue: VecDeque<f32>,
+  // capture time of the front of the queue
+  head_time: Option<Instant>,
+  channels: u16,
+  // reused for every read from the ring
+  chunk: Vec<f32>,
+}
+
+impl Lane {
+  /// Check on the source's task, false once it has finished
+  async fn poll(&mut self) -> Result<bool, anyhow::Error> {
+    let Some(handle) = &mut self.handle else {
+      return Ok(false);
+    };
+    if !handle.is_finished() {
+      return Ok(true);
+    }
+    let handle = self.handle.take().expect("checked above");
+    match handle.await {
+      Ok(Ok(())) => {
+        info!("mixed source {} finished...", self.source.backend);
+        Ok(false)
+      }
+      Ok(Err(e)) => Err(anyhow::anyhow!(
+        "mixed source {} failed - {}",
+        self.source.backend,
+        e
+      )),
+      Err(e) => Err(anyhow::anyhow!(
+        "mixed source {} panicked - {}",
+        self.source.backend,
+        e
+      )),
+    }
+  }
+
+  /// Move everything new in the source's ring onto the queue
+  fn pull(&mut self, rate: f64) {
+    while let Some(read) = self
+      .reader
+      .next(CHUNK_FRAMES, CHUNK_FRAMES, &mut self.chunk)
+    {
+      // a gap or a new layout means the queue no longer runs on from here
+      if read.discontinuity || read.format.channels != self.channels {
+        if !self.queue.is_empty() {
+          warn!(
+            "mixed source {} skipped, realigning...",
+            self.source.backend
+          );
+        }
+        self.queue.clear();
+        self.channels = read.format.channels;
+      }
+      let channels = self.channels.max(1) as usize;
+      // sources on their own clocks drift, so follow the timestamps once the
+      // queue is out by more than the timestamps jitter
+      let queued = Duration::from_secs_f64((self.queue.len() / channels) as f64 / rate);
+      let drifted = self.head_time.is_some_and(|head| {
+        let expected = head + queued;
+        expected.max(read.timestamp) - expected.min(read.timestamp) > DRIFT_TOLERANCE
+      });
+      if self.queue.is_empty() || drifted {
+        self.head_time = read.timestamp.checked_sub(queued);
+      }
+      self.queue.extend(&self.chunk);
+    }
+  }
+
+  /// Take `frames` frames starting at `start` into `out`, silence wherever
+  /// the source has nothing for that time
+  fn take(&mut self, start: Instant, frames: usize, rate: f64, out: &mut Vec<f32>) {
+    let channels = self.channels.max(1) as usize;
+    out.clear();
+
+    if let Some(head) = self.head_time {
+      // drop what is older than the block, the mix has moved past it
+      if head < start {
+        let late = ((start - head).as_secs_f64() * rate).round() as usize;
+        let late = late.min(self.queue.len() / channels);
+        self.queue.drain(..late * channels);
+        self.head_time = Some(head + Duration::from_secs_f64(late as f64 / rate));
+      }
+      // and hold back what is meant for later in or after it
+      if let Some(head) = self.head_time
+        && head > start
+      {
+        let early = ((head - start).as_secs_f64() * rate).round() as usize;
+        out.resize(early.min(frames) * channels, 0.0);
+      }
+    }
+
+    let wanted = frames * channels - out.len();
+    let available = wanted.min(self.queue.len());
+    out.extend(self.queue.drain(..available));
+    if let Some(head) = &mut self.head_time {
+      *head += Duration::from_secs_f64((available / channels) as f64 / rate);
+    }
+    out.resize(frames * channels, 0.0);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audio::ring::{RingFormat, RingMark, RingWriter};
+
+  // a frame a millisecond keeps lane timing easy to follow
+  const LANE_RATE: f64 = 1000.0;
+
+  fn source(backend: &str, gain: f32) -> MixSource {
+    MixSource {
+      backend: backend.to_string(),
+      device: None,
+      gain,
+    }
+  }
+
+  fn lane(ring: &SampleRing, gain: f32) -> Lane {
+    Lane {
+      source: source("test", gain),
+      reader: ring.reader(),
+      handle: None,
+      queue: VecDeque::new(),
+      head_time: None,
+      channels: 0,
+      chunk: Vec::new(),
+    }
+  }
+
+  /// A chunk of mono frames counting up from `first`, captured at `timestamp`
+  fn write(writer: &mut RingWriter, first: u32, timestamp: Instant) {
+    let samples: Vec<f32> = (first..first + CHUNK_FRAMES as u32)
+      .map(|n| n as f32)
+      .collect();
+    let format = RingFormat {
+      sample_rate: LANE_RATE as f32,
+      channels: 1,
+    };
+    let mark = RingMark {
+      timestamp,
+      position: first as u64,
+      sequence: first as u64,
+      discontinuity: false,
+      glitch: false,
+    };
+    writer.write(&samples, format, mark);
+  }
+
+  fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+  }
+
+  #[test]
+  fn take_drops_late_frames_and_pads_early_ones() {
+    let ring = SampleRing::new(1024);
+    let mut writer = ring.writer();
+    let mut lane = lane(&ring, 1.0);
+    let t0 = Instant::now();
+    write(&mut writer, 0, t0);
+    lane.pull(LANE_RATE);
+
+    let mut out = Vec::new();
+    // the mix has moved 3 frames past the head
+    lane.take(t0 + ms(3), 4, LANE_RATE, &mut out);
+    assert_eq!(out, [3.0, 4.0, 5.0, 6.0]);
+    // and is now 2 frames short of it
+    lane.take(t0 + ms(5), 4, LANE_RATE, &mut out);
+    assert_eq!(out, [0.0, 0.0, 7.0, 8.0]);
+    assert_eq!(lane.head_time, Some(t0 + ms(9)));
+  }
+
+  #[test]
+  fn pull_follows_timestamps_once_they_drift() {
+    let ring = SampleRing::new(1024);
+    let mut writer = ring.writer();
+    let mut lane = lane(&ring, 1.0);
+    let t0 = Instant::now();
+    let chunk = ms(CHUNK_FRAMES as u64);
+
+    // jitter inside the tolerance is left alone
+    write(&mut writer, 0, t0);
+    write(&mut writer, 32, t0 + chunk + ms(10));
+    lane.pull(LANE_RATE);
+    assert_eq!(lane.head_time, Some(t0));
+    assert_eq!(lane.queue.len(), 2 * CHUNK_FRAMES);
+
+    // past it, the queue is moved to where the newest chunk says
+    write(&mut writer, 64, t0 + 2 * chunk + ms(50));
+    lane.pull(LANE_RATE);
+    assert_eq!(lane.head_time, Some(t0 + ms(50)));
+
+    let mut out = Vec::new();
+    lane.take(t0 + ms(48), 4, LANE_RATE, &mut out);
+    assert_eq!(out, [0.0, 0.0, 0.0, 1.0]);
+  }
+
+  /// A lane that has taken in a chunk of `frame` repeated, captured at
+  /// `timestamp`
+  fn steady(ring: &SampleRing, gain: f32, frame: &[f32], timestamp: Instant) -> Lane {
+    let samples = frame.repeat(CHUNK_FRAMES);
+    let format = RingFormat {
+      sample_rate: LANE_RATE as f32,
+      channels: frame.len() as u16,
+    };
+    let mark = RingMark {
+      timestamp,
+      position: 0,
+      sequence: 0,
+      discontinuity: false,
+      glitch: false,
+    };
+    let mut lane = lane(ring, gain);
+    ring.writer().write(&samples, format, mark);
+    lane.pull(LANE_RATE);
+    lane
+  }
+
+  #[test]
+  fn sum_adds_sources_with_their_gains() {
+    let (mono, stereo) = (SampleRing::new(1024), SampleRing::new(1024));
+    let t0 = Instant::now();
+    let mut lanes = [
+      steady(&mono, 0.5, &[0.5], t0),
+      // starting a couple of frames into the block
+      steady(&stereo, 2.0, &[0.25, -0.25], t0 + ms(2)),
+    ];
+    let (mut lane_out, mut packet) = (Vec::new(), AudioPacket::default());
+    mix_block(
+      &mut lanes,
+      MixMode::Sum,
+      t0,
+      4,
+      LANE_RATE,
+      &mut lane_out,
+      &mut packet,
+    );
+
+    // as wide as the widest, the mono repeated across it
+    assert_eq!(packet.channels, 2);
+    assert_eq!(
+      packet.samples,
+      [0.25, 0.25, 0.25, 0.25, 0.75, -0.25, 0.75, -0.25]
+    );
+    assert_eq!(packet.timestamp, t0);
+    assert!(!packet.is_silent);
+  }
+
+  #[test]
+  fn split_lays_sources_side_by_side() {
+    let (mono, stereo) = (SampleRing::new(1024), SampleRing::new(1024));
+    let t0 = Instant::now();
+    let mut lanes = [
+      steady(&stereo, 1.0, &[0.25, -0.25], t0),
+      steady(&mono, 0.5, &[0.5], t0 + ms(1)),
+    ];
+    let (mut lane_out, mut packet) = (Vec::new(), AudioPacket::default());
+    mix_block(
+      &mut lanes,
+      MixMode::Split,
+      t0,
+      2,
+      LANE_RATE,
+      &mut lane_out,
+      &mut packet,
+    );
+
+    // the stereo's two channels, then the mono's one
+    assert_eq!(packet.channels, 3);
+    assert_eq!(packet.samples, [0.25, -0.25, 0.0, 0.25, -0.25, 0.25]);
+  }
+
+  /// A mono wav of `frames` frames all at `level`, removed when dropped
+  #[cfg(feature = "file")]
+  struct TempWav(std::path::PathBuf);
+
+  #[cfg(feature = "file")]
+  impl TempWav {
+    fn new(name: &str, frames: usize, level: i16) -> Self {
+      let path = std::env::temp_dir().join(format!("field-{}-{}.wav", std::process::id(), name));
+      let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: 48_000,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+      };
+      let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+      for _ in 0..frames {
+        writer.write_sample(level).unwrap();
+      }
+      writer.finalize().unwrap();
+      Self(path)
+    }
+
+    fn backend(&self, stop: &Arc<AtomicBool>) -> Box<dyn AudioBackend> {
+      Box::new(crate::audio::file::FileBackend::new(
+        crate::audio::test_config(),
+        self.0.clone(),
+        false,
+        Arc::clone(stop),
+      ))
+    }
+  }
+
+  #[cfg(feature = "file")]
+  impl Drop for TempWav {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_file(&self.0);
+    }
+  }
+
+  #[cfg(feature = "file")]
+  #[tokio::test]
+  async fn ends_once_every_source_has() {
+    let stop = Arc::new(AtomicBool::new(false));
+    let short = TempWav::new("short", 2048, i16::MAX / 2 + 1);
+    let long = TempWav::new("long", 8192, i16::MAX / 4 + 1);
+    let sources = vec![
+      (source("file", 0.5), short.backend(&stop)),
+      (source("file", 2.0), long.backend(&stop)),
+    ];
+    let config = crate::audio::test_config();
+    let ring = SampleRing::new(config.history_size);
+    let mut reader = ring.reader();
+    let mixer = MixerBackend::new(
+      config,
+      sources,
+      MixMode::Sum,
+      Arc::new(AtomicBool::new(false)),
+      stop,
+    );
+    let run = tokio::spawn(Box::new(mixer).run(AudioSender::new(ring.writer(), None)));
+    tokio::time::timeout(Duration::from_secs(10), run)
+      .await
+      .expect("mix never ended")
+      .unwrap()
+      .unwrap();
+
+    // however the two lined up, the longer one plays on alone at the end
+    let (mut out, mut chunk) = (Vec::new(), Vec::new());
+    while let Some(read) = reader.next(CHUNK_FRAMES, CHUNK_FRAMES, &mut chunk) {
+      assert_eq!(read.format.channels, 1);
+      out.extend(&chunk);
+    }
+    assert!(out.iter().all(|&s| [0.0, 0.25, 0.5, 0.75].contains(&s)));
+    assert!(out.contains(&0.5));
+  }
+}
@@ -1,12 +1,107 @@
+pub mod agc;
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+pub mod alsa;
 pub mod backend;
+pub mod cqt;
+#[cfg(feature = "file")]
+pub mod file;
+pub mod filter;
+#[cfg(any(
+  feature = "pipe",
+  feature = "net",
+  all(target_os = "linux", feature = "alsa"),
+  all(target_os = "windows", feature = "wasapi")
+))]
+pub mod format;
+#[cfg(feature = "generator")]
+pub mod generator;
+#[cfg(feature = "jack")]
+pub mod jack;
+#[cfg(feature = "mix")]
+pub mod mixer;
+#[cfg(feature = "net")]
+pub mod net;
+#[cfg(feature = "pipe")]
+pub mod pipe;
 pub mod processor;
-
-#[cfg(target_os = "windows")]
+#[cfg(all(target_os = "linux", feature = "pulse"))]
+pub mod pulse;
+#[cfg(feature = "file")]
+pub mod recorder;
+pub mod registry;
+pub mod resampler;
+pub mod ring;
+pub mod scale;
+pub mod supervisor;
+#[cfg(all(target_os = "windows", feature = "wasapi"))]
 pub mod wasapi;
+pub mod window;
+
+use agc::AgcConfig;
+use filter::FilterSpec;
+use processor::{Channel, Magnitude, Transform};
+use scale::FrequencyScale;
+use window::WindowFunction;
 
 #[derive(Clone)]
 pub struct AudioConfig {
+  // window length in frames
   pub fft_size: usize,
+  // frames between the starts of successive windows
+  pub hop_size: usize,
+  // fft length as a multiple of the window, the rest zeros
+  pub zero_padding: usize,
+  // applied to each block before the fft
+  pub window: WindowFunction,
+  // how bin magnitudes are worked out and scaled for display
+  pub magnitude: Magnitude,
+  // what the bars come from
+  pub transform: Transform,
   pub buffer_size: usize,
+  // every backend is resampled to this before analysis, none keeps theirs
+  pub analysis_rate: Option<u32>,
+  // samples of history kept between capture and analysis
+  pub history_size: usize,
+  // bands shown, which octave scales decide for themselves
   pub bar_count: usize,
+  // how bars are spread between the lowest and highest frequency shown
+  pub scale: FrequencyScale,
+  pub min_freq: f32,
+  pub max_freq: f32,
+  // which signal the visuals follow
+  pub channel: Channel,
+  // how the spectrum is brought to a steady level
+  pub agc: AgcConfig,
+  // run over each signal in order before it is windowed
+  pub filters: Vec<FilterSpec>,
+}
+
+/// Defaults the app runs with, for tests to start from
+#[cfg(test)]
+pub fn test_config() -> AudioConfig {
+  use std::time::Duration;
+
+  AudioConfig {
+    fft_size: 1024,
+    hop_size: 256,
+    zero_padding: 1,
+    window: WindowFunction::Hann,
+    magnitude: Magnitude::Approximate,
+    transform: Transform::Fft,
+    buffer_size: 2048,
+    analysis_rate: Some(48_000),
+    history_size: 1 << 19,
+    bar_count: 64,
+    scale: FrequencyScale::Logarithmic,
+    min_freq: 20.0,
+    max_freq: 20_000.0,
+    channel: Channel::Mono,
+    agc: AgcConfig {
+      target: 0.4,
+      attack: Duration::from_millis(100),
+      release: Duration::from_millis(4000),
+      max_gain: 1000.0,
+    },
+    filters: Vec::new(),
+  }
 }
@@ -0,0 +1,600 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use tokio::task;
+
+use tracing::{debug, info, warn};
+
+use crate::audio::backend::{AudioBackend, AudioPacket, AudioSender};
+use crate::audio::format::{Endian, SampleFormat, SampleType};
+
+// how long the socket blocks, short enough to release held packets on time
+const RECV_TIMEOUT: Duration = Duration::from_millis(5);
+// largest gap from lost packets filled with silence, anything longer is only
+// flagged
+const MAX_CONCEAL: Duration = Duration::from_millis(100);
+
+/// Listens on a udp port for interleaved pcm, either bare datagrams or rtp
+/// (RFC 3550) carrying L16, L24 or 32-bit float payloads.
+///
+/// Rtp goes through a jitter buffer that puts packets back in order, fills
+/// short losses with silence and maps rtp timestamps onto our clock. Bare
+/// datagrams are passed on as they arrive. The backend runs until stopped,
+/// with the supervisor showing silence whenever nothing is coming in.
+pub struct NetBackend {
+  addr: SocketAddr,
+  rtp: bool,
+  // format of bare and dynamic payload type packets
+  format: SampleFormat,
+  sample_rate: u32,
+  channels: u16,
+  jitter: Duration,
+  stop: Arc<AtomicBool>,
+}
+
+impl NetBackend {
+  pub fn new(
+    addr: SocketAddr,
+    rtp: bool,
+    format: SampleFormat,
+    sample_rate: u32,
+    channels: u16,
+    jitter: Duration,
+    stop: Arc<AtomicBool>,
+  ) -> Self {
+    Self {
+      addr,
+      rtp,
+      format,
+      sample_rate,
+      channels,
+      jitter,
+      stop,
+    }
+  }
+
+  /// Parse `HOST:PORT`, or a bare port to listen on every interface
+  pub fn parse_addr(addr: &str) -> Result<SocketAddr, anyhow::Error> {
+    if let Ok(port) = addr.parse::<u16>() {
+      return Ok(SocketAddr::from(([0, 0, 0, 0], port)));
+    }
+    addr.parse().map_err(|_| {
+      anyhow::anyhow!(
+        "invalid listen address '{}', expected HOST:PORT or a port",
+        addr
+      )
+    })
+  }
+}
+
+#[async_trait]
+impl AudioBackend for NetBackend {
+  async fn run(self: Box<Self>, tx: AudioSender) -> Result<(), anyhow::Error> {
+    task::spawn_blocking(move || receive_loop(*self, tx)).await??;
+    Ok(())
+  }
+}
+
+fn receive_loop(backend: NetBackend, mut tx: AudioSender) -> Result<(), anyhow::Error> {
+  if backend.channels == 0 || backend.sample_rate == 0 {
+    return Err(anyhow::anyhow!(
+      "network input needs a non-zero rate and channel count"
+    ));
+  }
+
+  let socket = UdpSocket::bind(backend.addr)?;
+  receive_on(&socket, backend, &mut tx)
+}
+
+/// Read pcm off a bound `socket` until stopped
+fn receive_on(
+  socket: &UdpSocket,
+  backend: NetBackend,
+  tx: &mut AudioSender,
+) -> Result<(), anyhow::Error> {
+  socket.set_read_timeout(Some(RECV_TIMEOUT))?;
+  info!(
+    "listening for {} {} pcm on udp {}...",
+    if backend.rtp { "rtp" } else { "bare" },
+    backend.format,
+    socket.local_addr()?
+  );
+
+  let fallback = PayloadFormat {
+    format: backend.format,
+    sample_rate: backend.sample_rate,
+    channels: backend.channels,
+  };
+  // the largest a udp payload can be
+  let mut buf = vec![0u8; 65_536];
+  let mut packet = AudioPacket::default();
+  let mut jitter = JitterBuffer::new(backend.jitter);
+  let mut stream = RtpStream::default();
+  // datagrams that weren't rtp, only the first is worth a warning
+  let mut not_rtp = 0u64;
+
+  while !backend.stop.load(Ordering::Relaxed) {
+    let received = match socket.recv_from(&mut buf) {
+      Ok((len, _)) => Some(len),
+      Err(e)
+        if matches!(
+          e.kind(),
+          io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ) =>
+      {
+        None
+      }
+      Err(e) if e.kind() == io::ErrorKind::Interrupted => None,
+      Err(e) => return Err(e.into()),
+    };
+    let arrival = Instant::now();
+
+    if !backend.rtp {
+      if let Some(len) = received {
+        fallback.decode(&buf[..len], &mut packet);
+        packet.timestamp = arrival - packet.duration();
+        tx.write(&mut packet);
+      }
+      continue;
+    }
+
+    if let Some(len) = received {
+      match RtpHeader::parse(&buf[..len]) {
+        Some((header, payload)) => {
+          if jitter.ssrc != Some(header.ssrc) {
+            if jitter.ssrc.is_some() {
+              info!("new rtp stream {:08x}, resetting...", header.ssrc);
+            }
+            jitter.reset(header.ssrc);
+            stream = RtpStream::default();
+          }
+          jitter.push(&header, payload, arrival);
+        }
+        None => {
+          not_rtp += 1;
+          if not_rtp == 1 {
+            warn!("ignoring datagrams that aren't rtp...");
+          } else {
+            debug!("{} datagrams that aren't rtp ignored", not_rtp);
+          }
+        }
+      }
+    }
+
+    while let Some(held) = jitter.pop(Instant::now()) {
+      let format = PayloadFormat::for_payload_type(held.payload_type).unwrap_or(fallback);
+      stream.play(held, format, &mut packet, tx);
+    }
+  }
+
+  info!("network input stopped...");
+  Ok(())
+}
+
+/// How the samples in a payload are laid out
+#[derive(Clone, Copy)]
+struct PayloadFormat {
+  format: SampleFormat,
+  sample_rate: u32,
+  channels: u16,
+}
+
+impl PayloadFormat {
+  /// The static payload types of RFC 3551, the rest need telling
+  fn for_payload_type(payload_type: u8) -> Option<Self> {
+    let l16 = SampleFormat::new(SampleType::I16, Endian::Big);
+    match payload_type {
+      10 => Some(Self {
+        format: l16,
+        sample_rate: 44_100,
+        channels: 2,
+      }),
+      11 => Some(Self {
+        format: l16,
+        sample_rate: 44_100,
+        channels: 1,
+      }),
+      _ => None,
+    }
+  }
+
+  /// Fill `packet` with the whole frames in `payload`
+  fn decode(self, payload: &[u8], packet: &mut AudioPacket) {
+    let frame_bytes = self.format.bytes_per_sample() * self.channels as usize;
+    let usable = payload.len() - payload.len() % frame_bytes;
+    packet.sample_rate = self.sample_rate as f32;
+    packet.channels = self.channels;
+    packet.samples.clear();
+    self
+      .format
+      .decode_into(&payload[..usable], &mut packet.samples);
+    packet.is_silent = packet.samples.iter().all(|&s| s == 0.0);
+  }
+}
+
+/// The fixed part of an rtp header
+struct RtpHeader {
+  payload_type: u8,
+  sequence: u16,
+  timestamp: u32,
+  ssrc: u32,
+}
+
+impl RtpHeader {
+  /// Split a datagram into its header and payload, none if it isn't rtp
+  fn parse(datagram: &[u8]) -> Option<(Self, &[u8])> {
+    if datagram.len() < 12 || datagram[0] >> 6 != 2 {
+      return None;
+    }
+    let padding = datagram[0] & 0x20 != 0;
+    let extension = datagram[0] & 0x10 != 0;
+    let csrc_count = (datagram[0] & 0x0f) as usize;
+
+    let header = Self {
+      payload_type: datagram[1] & 0x7f,
+      sequence: u16::from_be_bytes([datagram[2], datagram[3]]),
+      timestamp: u32::from_be_bytes(datagram[4..8].try_into().ok()?),
+      ssrc: u32::from_be_bytes(datagram[8..12].try_into().ok()?),
+    };
+
+    let mut start = 12 + 4 * csrc_count;
+    if extension {
+      let words = datagram.get(start + 2..start + 4)?;
+      start += 4 + 4 * u16::from_be_bytes([words[0], words[1]]) as usize;
+    }
+    let mut end = datagram.len();
+    if padding {
+      end = end.checked_sub(*datagram.last()? as usize)?;
+    }
+    let payload = datagram.get(start..end)?;
+    Some((header, payload))
+  }
+}
+
+/// A packet waiting its turn in the jitter buffer
+struct Held {
+  payload_type: u8,
+  // extended to 64 bits so wrapping doesn't matter
+  timestamp: u64,
+  arrival: Instant,
+  payload: Vec<u8>,
+  // packets skipped over to get to this one
+  lost: u64,
+}
+
+/// Holds rtp packets for a moment so late ones can be slotted back in order.
+///
+/// A packet is released as soon as everything before it has been, or once
+/// the oldest held packet has waited out the delay, at which point whatever
+/// is still missing is given up on.
+struct JitterBuffer {
+  delay: Duration,
+  ssrc: Option<u32>,
+  // by extended sequence number
+  held: BTreeMap<u64, Held>,
+  // extended sequence number to release next, none until the first release
+  next: Option<u64>,
+  // newest extended sequence number seen, what new ones are extended against
+  newest: Option<u64>,
+  newest_timestamp: Option<u64>,
+}
+
+impl JitterBuffer {
+  fn new(delay: Duration) -> Self {
+    Self {
+      delay,
+      ssrc: None,
+      held: BTreeMap::new(),
+      next: None,
+      newest: None,
+      newest_timestamp: None,
+    }
+  }
+
+  /// Start over on a new stream
+  fn reset(&mut self, ssrc: u32) {
+    *self = Self::new(self.delay);
+    self.ssrc = Some(ssrc);
+  }
+
+  fn push(&mut self, header: &RtpHeader, payload: &[u8], arrival: Instant) {
+    let sequence = extend(self.newest, header.sequence as u64, 16);
+    let timestamp = extend(self.newest_timestamp, header.timestamp as u64, 32);
+    if self.newest.is_none_or(|newest| sequence > newest) {
+      self.newest = Some(sequence);
+      self.newest_timestamp = Some(timestamp);
+    }
+
+    // too late, its turn has been and gone
+    if self.next.is_some_and(|next| sequence < next) {
+      return;
+    }
+    self.held.entry(sequence).or_insert_with(|| Held {
+      payload_type: header.payload_type,
+      timestamp,
+      arrival,
+      payload: payload.to_vec(),
+      lost: 0,
+    });
+  }
+
+  /// The next packet in order, if it is time for one
+  fn pop(&mut self, now: Instant) -> Option<Held> {
+    let (&sequence, oldest) = self.held.first_key_value()?;
+    let in_order = self.next == Some(sequence);
+    if !in_order && now.duration_since(oldest.arrival) < self.delay {
+      return None;
+    }
+
+    let mut held = self.held.remove(&sequence)?;
+    held.lost = self.next.map_or(0, |next| sequence - next);
+    self.next = Some(sequence + 1);
+    Some(held)
+  }
+}
+
+/// Widen a wrapping counter of `bits` bits, picking whichever value lies
+/// closest to the newest one seen
+fn extend(newest: Option<u64>, value: u64, bits: u32) -> u64 {
+  let Some(newest) = newest else {
+    // start a cycle in, so values from just before the first don't underflow
+    return value + (1 << bits);
+  };
+  let cycle = 1u64 << bits;
+  let base = (newest & !(cycle - 1)) | value;
+  [base.wrapping_sub(cycle), base, base + cycle]
+    .into_iter()
+    .min_by_key(|candidate| candidate.abs_diff(newest))
+    .unwrap_or(base)
+}
+
+/// Where the released packets of one rtp stream are up to
+#[derive(Default)]
+struct RtpStream {
+  // rtp timestamp the next packet should start at
+  expected: Option<u64>,
+  // the first timestamp, positions count from it
+  first: Option<u64>,
+  // our clock at the first timestamp, as seen through the quickest packet
+  reference: Option<Instant>,
+}
+
+impl RtpStream {
+  /// Send a released packet on, first filling any gap before it with silence
+  fn play(
+    &mut self,
+    held: Held,
+    format: PayloadFormat,
+    packet: &mut AudioPacket,
+    tx: &mut AudioSender,
+  ) {
+    let first = *self.first.get_or_insert(held.timestamp);
+    let rate = format.sample_rate as f64;
+    let offset = Duration::from_secs_f64(held.timestamp.saturating_sub(first) as f64 / rate);
+
+    // whichever packet had the shortest trip says best when the sender sent
+    let sent = held.arrival.checked_sub(offset).unwrap_or(held.arrival);
+    let reference = *self
+      .reference
+      .insert(self.reference.map_or(sent, |reference| reference.min(sent)));
+
+    let gap = self
+      .expected
+      .map_or(0, |expected| held.timestamp.saturating_sub(expected));
+    if held.lost > 0 || gap > 0 {
+      packet.discontinuity = true;
+      let max_conceal = (MAX_CONCEAL.as_secs_f64() * rate) as u64;
+      if gap > 0 && gap <= max_conceal {
+        // keep time with silence where the lost packets would have been
+        let start = held.timestamp - gap;
+        packet.sample_rate = format.sample_rate as f32;
+        packet.channels = format.channels;
+        packet.samples.clear();
+        packet
+          .samples
+          .resize(gap as usize * format.channels as usize, 0.0);
+        packet.is_silent = true;
+        packet.position = start - first;
+        packet.timestamp = reference + Duration::from_secs_f64((start - first) as f64 / rate);
+        tx.write(packet);
+      }
+    }
+
+    format.decode(&held.payload, packet);
+    packet.position = held.timestamp - first;
+    packet.timestamp = reference + offset;
+    self.expected = Some(held.timestamp + packet.frames() as u64);
+    tx.write(packet);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audio::ring::{RingRead, RingReader, SampleRing};
+
+  use std::thread::{self, JoinHandle};
+
+  const SSRC: u32 = 0x1234_5678;
+
+  /// A receiver on a loopback port, and the socket to send to it from
+  struct Listener {
+    sender: UdpSocket,
+    reader: RingReader,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+  }
+
+  impl Listener {
+    fn start(rtp: bool, format: &str) -> Self {
+      let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+      let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+      sender.connect(socket.local_addr().unwrap()).unwrap();
+
+      let ring = SampleRing::new(1 << 16);
+      let reader = ring.reader();
+      let mut tx = AudioSender::new(ring.writer(), None);
+      let stop = Arc::new(AtomicBool::new(false));
+      let backend = NetBackend::new(
+        socket.local_addr().unwrap(),
+        rtp,
+        format.parse().unwrap(),
+        48_000,
+        2,
+        Duration::from_millis(20),
+        Arc::clone(&stop),
+      );
+      let handle = thread::spawn(move || receive_on(&socket, backend, &mut tx).unwrap());
+      Self {
+        sender,
+        reader,
+        stop,
+        handle,
+      }
+    }
+
+    fn send(&self, datagram: &[u8]) {
+      self.sender.send(datagram).unwrap();
+    }
+
+    /// The next `frames` frames, waiting a while for them to come through
+    fn read(&mut self, frames: usize) -> (Vec<f32>, RingRead) {
+      let mut out = Vec::new();
+      let deadline = Instant::now() + Duration::from_secs(2);
+      loop {
+        if let Some(read) = self.reader.next(frames, frames, &mut out) {
+          return (out, read);
+        }
+        assert!(Instant::now() < deadline, "{} frames never came", frames);
+        thread::sleep(Duration::from_millis(1));
+      }
+    }
+
+    fn finish(self) {
+      self.stop.store(true, Ordering::Relaxed);
+      self.handle.join().unwrap();
+    }
+  }
+
+  /// Rtp datagram of big-endian 16-bit `samples`
+  fn rtp(payload_type: u8, sequence: u16, timestamp: u32, samples: &[i16]) -> Vec<u8> {
+    let mut datagram = vec![0x80, payload_type];
+    datagram.extend(sequence.to_be_bytes());
+    datagram.extend(timestamp.to_be_bytes());
+    datagram.extend(SSRC.to_be_bytes());
+    datagram.extend(samples.iter().flat_map(|s| s.to_be_bytes()));
+    datagram
+  }
+
+  /// Four mono frames of `n` eighths of full scale, for packet `n`
+  fn frames(n: i16) -> [i16; 4] {
+    [n * 4096; 4]
+  }
+
+  fn values(n: i16) -> [f32; 4] {
+    [n as f32 / 8.0; 4]
+  }
+
+  #[test]
+  fn bare_datagrams_pass_straight_through() {
+    let mut listener = Listener::start(false, "s16le");
+    let samples: [i16; 6] = [16_384, -16_384, 8_192, -8_192, 0, i16::MIN];
+    let datagram: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    // with a trailing partial frame
+    listener.send(&[&datagram[..], &[1, 2]].concat());
+
+    let (out, read) = listener.read(3);
+    assert_eq!(out, [0.5, -0.5, 0.25, -0.25, 0.0, -1.0]);
+    assert_eq!(read.format.sample_rate, 48_000.0);
+    assert_eq!(read.format.channels, 2);
+    listener.finish();
+  }
+
+  #[test]
+  fn static_payload_types_set_the_format() {
+    let mut listener = Listener::start(true, "f32le");
+    listener.send(&rtp(10, 1, 0, &[4096, -4096, 8192, -8192]));
+    let (out, read) = listener.read(2);
+    assert_eq!(out, [0.125, -0.125, 0.25, -0.25]);
+    assert_eq!(read.format.sample_rate, 44_100.0);
+    assert_eq!(read.format.channels, 2);
+
+    listener.send(&rtp(11, 2, 2, &frames(3)));
+    let (out, read) = listener.read(4);
+    assert_eq!(out, values(3));
+    assert_eq!(read.format.channels, 1);
+    listener.finish();
+  }
+
+  #[test]
+  fn reordered_packets_come_out_in_sequence() {
+    let mut listener = Listener::start(true, "f32le");
+    for n in [1, 3, 2, 4] {
+      listener.send(&rtp(11, n as u16, 4 * n as u32, &frames(n)));
+    }
+    let (out, read) = listener.read(16);
+    assert_eq!(out, [values(1), values(2), values(3), values(4)].concat());
+    assert!(!read.discontinuity);
+    listener.finish();
+  }
+
+  #[test]
+  fn lost_packets_are_filled_and_flagged() {
+    let mut listener = Listener::start(true, "f32le");
+    for n in [1, 2, 4] {
+      listener.send(&rtp(11, n as u16, 4 * n as u32, &frames(n)));
+    }
+    let (out, read) = listener.read(8);
+    assert_eq!(out, [values(1), values(2)].concat());
+    assert!(!read.discontinuity);
+
+    let (out, read) = listener.read(8);
+    assert_eq!(out, [[0.0; 4], values(4)].concat());
+    assert!(read.discontinuity);
+    listener.finish();
+  }
+
+  #[test]
+  fn sequence_numbers_wrap() {
+    let mut listener = Listener::start(true, "f32le");
+    for (i, sequence) in [65_534u16, 65_535, 0, 1].into_iter().enumerate() {
+      let n = i as i16 + 1;
+      listener.send(&rtp(11, sequence, 4 * n as u32, &frames(n)));
+    }
+    let (out, read) = listener.read(16);
+    assert_eq!(out, [values(1), values(2), values(3), values(4)].concat());
+    assert!(!read.discontinuity);
+    listener.finish();
+  }
+
+  #[test]
+  fn header_skips_csrcs_extension_and_padding() {
+    let mut datagram = rtp(96, 7, 1000, &[]);
+    // one csrc, an extension and padding
+    datagram[0] |= 0x20 | 0x10 | 0x01;
+    datagram.extend([0xaa; 4]);
+    datagram.extend([0xbe, 0xde, 0x00, 0x02]);
+    datagram.extend([0xbb; 8]);
+    datagram.extend([1, 2, 3, 4]);
+    datagram.extend([0, 0, 3]);
+
+    let (header, payload) = RtpHeader::parse(&datagram).unwrap();
+    assert_eq!(header.payload_type, 96);
+    assert_eq!(header.sequence, 7);
+    assert_eq!(header.timestamp, 1000);
+    assert_eq!(header.ssrc, SSRC);
+    assert_eq!(payload, [1, 2, 3, 4]);
+
+    // padding longer than the packet, and a version 1 header
+    let last = datagram.len() - 1;
+    datagram[last] = 255;
+    assert!(RtpHeader::parse(&datagram).is_none());
+    assert!(RtpHeader::parse(&[0x40; 12]).is_none());
+  }
+}
@@ -0,0 +1,202 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use tokio::task;
+
+use tracing::info;
+
+use crate::audio::AudioConfig;
+use crate::audio::backend::{AudioBackend, AudioPacket, AudioSender};
+use crate::audio::format::SampleFormat;
+
+// how long a read waits on the pipe before checking whether to stop
+#[cfg(unix)]
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reads raw interleaved pcm from stdin or a named fifo.
+///
+/// The producer sets the pace, so tools reading from a file should be run in
+/// real-time mode (e.g. `ffmpeg -re`). The backend returns once the pipe closes.
+///
+/// On unix the pipe is polled so a stalled or idle producer doesn't hold up
+/// shutdown. Elsewhere reads block, and stopping waits on the producer's next
+/// write or on eof.
+pub struct PipeBackend {
+  config: AudioConfig,
+  // none reads stdin
+  path: Option<PathBuf>,
+  format: SampleFormat,
+  sample_rate: u32,
+  channels: u16,
+  stop: Arc<AtomicBool>,
+}
+
+impl PipeBackend {
+  pub fn new(
+    config: AudioConfig,
+    path: Option<PathBuf>,
+    format: SampleFormat,
+    sample_rate: u32,
+    channels: u16,
+    stop: Arc<AtomicBool>,
+  ) -> Self {
+    Self {
+      config,
+      path,
+      format,
+      sample_rate,
+      channels,
+      stop,
+    }
+  }
+}
+
+#[async_trait]
+impl AudioBackend for PipeBackend {
+  async fn run(self: Box<Self>, tx: AudioSender) -> Result<(), anyhow::Error> {
+    task::spawn_blocking(move || read_loop(*self, tx)).await??;
+    Ok(())
+  }
+}
+
+fn read_loop(backend: PipeBackend, mut tx: AudioSender) -> Result<(), anyhow::Error> {
+  if backend.channels == 0 || backend.sample_rate == 0 {
+    return Err(anyhow::anyhow!(
+      "pcm input needs a non-zero rate and channel count"
+    ));
+  }
+
+  let mut reader = match &backend.path {
+    Some(path) => open_fifo(path)?,
+    None => stdin()?,
+  };
+
+  info!(
+    "reading {} pcm ({} Hz, {} channels) from {}...",
+    backend.format,
+    backend.sample_rate,
+    backend.channels,
+    backend
+      .path
+      .as_ref()
+      .map_or_else(|| "stdin".to_string(), |p| p.display().to_string())
+  );
+
+  let frame_bytes = backend.format.bytes_per_sample() * backend.channels as usize;
+  let frames_per_packet = backend.config.fft_size.max(1);
+  let mut bytes_buf = vec![0u8; frames_per_packet * frame_bytes];
+  let mut packet = AudioPacket::new(
+    backend.sample_rate as f32,
+    backend.channels,
+    frames_per_packet * backend.channels as usize,
+  );
+
+  while !backend.stop.load(Ordering::Relaxed) {
+    let filled = read_full(&mut reader, &mut bytes_buf, &backend.stop)?;
+    // drop any partial frame, only possible right before eof
+    let usable = filled - filled % frame_bytes;
+    if usable == 0 {
+      break;
+    }
+
+    packet.samples.clear();
+    backend
+      .format
+      .decode_into(&bytes_buf[..usable], &mut packet.samples);
+    packet.is_silent = packet.samples.iter().all(|&s| s == 0.0);
+    // the writer's clock is unknown, the best we have is when it arrived
+    packet.timestamp = Instant::now() - packet.duration();
+
+    tx.write(&mut packet);
+
+    if filled < bytes_buf.len() {
+      break;
+    }
+  }
+
+  info!("pcm input closed...");
+  Ok(())
+}
+
+/// Fill `buf` unless the pipe closes or we are stopped first, returning the
+/// bytes read
+fn read_full(reader: &mut File, buf: &mut [u8], stop: &AtomicBool) -> Result<usize, io::Error> {
+  let mut filled = 0;
+  while filled < buf.len() && wait_readable(reader, stop)? {
+    match reader.read(&mut buf[filled..]) {
+      Ok(0) => break,
+      Ok(n) => filled += n,
+      Err(e)
+        if matches!(
+          e.kind(),
+          io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+        ) =>
+      {
+        continue;
+      }
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(filled)
+}
+
+/// Open a fifo without waiting for a writer to show up, the wait moves to the
+/// reads where it can be stopped
+#[cfg(unix)]
+fn open_fifo(path: &std::path::Path) -> Result<File, io::Error> {
+  use std::os::unix::fs::OpenOptionsExt;
+  File::options()
+    .read(true)
+    .custom_flags(libc::O_NONBLOCK)
+    .open(path)
+}
+
+#[cfg(not(unix))]
+fn open_fifo(path: &std::path::Path) -> Result<File, io::Error> {
+  File::open(path)
+}
+
+/// Our own handle on stdin, to wait on and read like a fifo
+fn stdin() -> Result<File, io::Error> {
+  #[cfg(unix)]
+  let handle = std::os::fd::AsFd::as_fd(&io::stdin()).try_clone_to_owned()?;
+  #[cfg(windows)]
+  let handle = std::os::windows::io::AsHandle::as_handle(&io::stdin()).try_clone_to_owned()?;
+  Ok(File::from(handle))
+}
+
+/// Wait until `file` has data or has closed, false if we were stopped first
+#[cfg(unix)]
+fn wait_readable(file: &File, stop: &AtomicBool) -> Result<bool, io::Error> {
+  use std::os::fd::AsRawFd;
+  let mut poll_fd = libc::pollfd {
+    fd: file.as_raw_fd(),
+    events: libc::POLLIN,
+    revents: 0,
+  };
+  while !stop.load(Ordering::Relaxed) {
+    match unsafe { libc::poll(&mut poll_fd, 1, POLL_INTERVAL.as_millis() as libc::c_int) } {
+      -1 => {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+          return Err(e);
+        }
+      }
+      0 => {}
+      _ => return Ok(true),
+    }
+  }
+  Ok(false)
+}
+
+#[cfg(not(unix))]
+fn wait_readable(_file: &File, stop: &AtomicBool) -> Result<bool, io::Error> {
+  // nothing to wait on here, the read itself blocks
+  Ok(!stop.load(Ordering::Relaxed))
+}
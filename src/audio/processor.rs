@@ -1,38 +1,381 @@
+use std::str::FromStr;
 use std::sync::Arc;
-
-use apodize::hanning_iter;
+use std::time::Duration;
 
 use realfft::{RealFftPlanner, RealToComplex};
 use rustfft::num_complex::Complex;
 
 use crate::audio::AudioConfig;
+use crate::audio::agc::AutoGain;
+use crate::audio::cqt::{ConstantQ, ConstantQCache};
+use crate::audio::filter::{Filter, FilterChain};
+use crate::audio::window::{coherent_gain, enbw};
+
+// how much of a bar carries over from one analysis to the next, and how much
+// it keeps through silence, at the reference hop
+const SMOOTH_FACTOR: f32 = 0.85;
+const DECAY_FACTOR: f32 = 0.95;
+// constant-q bins to the octave unless given
+const DEFAULT_BINS_PER_OCTAVE: u32 = 24;
+
+// treble boost at each frequency, so music looks even across the bars
+const COMPENSATION: &[(f32, f32)] = &[(20.0, 0.2), (50.0, 0.5), (650.0, 1.0), (20_000.0, 2.0)];
 
 struct BandInfo {
+  // first bin the band draws on
   bin_low: usize,
-  bin_high: usize,
+  // how much of each bin from `bin_low` on falls in the band, in bins, empty
+  // when past nyquist
+  weights: Vec<f32>,
   compensation: f32,
 }
 
+/// Which signal of a multichannel stream to look at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+  /// Average of every channel
+  Mono,
+  /// Sum of left and right, what stereo has in common
+  Mid,
+  /// Difference of left and right, the stereo width
+  Side,
+  /// A single channel, counting from 0
+  Index(u16),
+}
+
+impl Channel {
+  /// This channel's sample of one interleaved frame, a mono frame counts as
+  /// both left and right
+  pub fn sample(self, frame: &[f32]) -> f32 {
+    let left = frame.first().copied().unwrap_or(0.0);
+    let right = frame.get(1).copied().unwrap_or(left);
+    match self {
+      Channel::Mono => frame.iter().sum::<f32>() / frame.len().max(1) as f32,
+      Channel::Mid => (left + right) * 0.5,
+      Channel::Side => (left - right) * 0.5,
+      Channel::Index(i) => frame.get(i as usize).copied().unwrap_or(0.0),
+    }
+  }
+
+  /// Pull this channel out of interleaved `samples` into `out`
+  pub fn extract(self, samples: &[f32], channels: u16, out: &mut Vec<f32>) {
+    out.clear();
+    out.extend(
+      samples
+        .chunks_exact(channels.max(1) as usize)
+        .map(|frame| self.sample(frame)),
+    );
+  }
+
+  // where the channel's analysis sits, the derived signals come first
+  fn slot(self) -> usize {
+    match self {
+      Channel::Mono => 0,
+      Channel::Mid => 1,
+      Channel::Side => 2,
+      Channel::Index(i) => 3 + i as usize,
+    }
+  }
+
+  fn from_slot(slot: usize) -> Self {
+    match slot {
+      0 => Channel::Mono,
+      1 => Channel::Mid,
+      2 => Channel::Side,
+      i => Channel::Index((i - 3) as u16),
+    }
+  }
+}
+
+impl FromStr for Channel {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    // channels are numbered from 1 on the command line, like most mixers
+    match s.to_ascii_lowercase().as_str() {
+      "mono" => Ok(Channel::Mono),
+      "mid" => Ok(Channel::Mid),
+      "side" => Ok(Channel::Side),
+      "left" | "l" => Ok(Channel::Index(0)),
+      "right" | "r" => Ok(Channel::Index(1)),
+      n => match n.parse::<u16>() {
+        Ok(n) if n >= 1 => Ok(Channel::Index(n - 1)),
+        _ => Err(anyhow::anyhow!(
+          "unknown channel '{}', expected mono, mid, side, left, right or a channel number from 1",
+          s
+        )),
+      },
+    }
+  }
+}
+
+/// How bin magnitudes are worked out and brought onto the display
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Magnitude {
+  /// Alpha max plus beta min estimate of `|X|`, cheap and within 4%, with
+  /// automatic gain
+  Approximate,
+  /// Exact `|X|`, with automatic gain
+  Exact,
+  /// `|X|` squared, with automatic gain
+  Power,
+  /// Dbfs, a full scale sine reading 0, with `floor..ceiling` spread over the
+  /// height of the display
+  Decibels { floor: f32, ceiling: f32 },
+}
+
+impl FromStr for Magnitude {
+  type Err = anyhow::Error;
+
+  /// Parses `approximate`, `exact`, `power` and `db[:FLOOR[:CEILING]]`, the
+  /// range defaulting to -90 to 0
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parts = s.split(':');
+    let kind = parts.next().unwrap_or_default().to_ascii_lowercase();
+    let mut number = |default: f32| match parts.next() {
+      Some(v) => v
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|v| v.is_finite())
+        .ok_or_else(|| anyhow::anyhow!("invalid level '{}' in '{}'", v, s)),
+      None => Ok(default),
+    };
+
+    let magnitude = match kind.as_str() {
+      "approximate" | "approx" => Magnitude::Approximate,
+      "exact" => Magnitude::Exact,
+      "power" => Magnitude::Power,
+      "db" | "dbfs" => {
+        let floor = number(-90.0)?;
+        let ceiling = number(0.0)?;
+        if floor >= ceiling {
+          return Err(anyhow::anyhow!(
+            "db floor {} must be below the ceiling {}",
+            floor,
+            ceiling
+          ));
+        }
+        Magnitude::Decibels { floor, ceiling }
+      }
+      _ => {
+        return Err(anyhow::anyhow!(
+          "unknown magnitude '{}', expected approximate, exact, power or db",
+          s
+        ));
+      }
+    };
+    if parts.next().is_some() {
+      return Err(anyhow::anyhow!("too many parameters in '{}'", s));
+    }
+    Ok(magnitude)
+  }
+}
+
+impl Magnitude {
+  /// What automatic gain works on for an amplitude, none when the display is
+  /// calibrated instead
+  fn level(self, amplitude: f32) -> Option<f32> {
+    match self {
+      Magnitude::Approximate | Magnitude::Exact => Some(amplitude),
+      Magnitude::Power => Some(amplitude * amplitude),
+      Magnitude::Decibels { .. } => None,
+    }
+  }
+
+  /// Height on the display, 0..=1, of an amplitude with `gain` applied
+  fn display(self, amplitude: f32, gain: f32) -> f32 {
+    match self {
+      Magnitude::Decibels { floor, ceiling } => {
+        let db = 20.0 * amplitude.max(f32::MIN_POSITIVE).log10();
+        ((db - floor) / (ceiling - floor)).clamp(0.0, 1.0)
+      }
+      _ => (self.level(amplitude).unwrap_or_default() * gain).min(1.0),
+    }
+  }
+}
+
+/// What the bars are worked out from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transform {
+  /// The short-time fourier transform, bins evenly spaced in frequency
+  Fft,
+  /// Bins evenly spaced in pitch, each as wide as the gap to the next
+  ConstantQ { bins_per_octave: u32 },
+}
+
+impl FromStr for Transform {
+  type Err = anyhow::Error;
+
+  /// Parses `fft` and `cqt[:BINS_PER_OCTAVE]`, 24 to the octave by default
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (kind, param) = s.split_once(':').unwrap_or((s, ""));
+    match kind.to_ascii_lowercase().as_str() {
+      "fft" if param.is_empty() => Ok(Transform::Fft),
+      "cqt" | "constant-q" => {
+        let bins_per_octave = match param.trim() {
+          "" => DEFAULT_BINS_PER_OCTAVE,
+          n => n
+            .parse::<u32>()
+            .ok()
+            .filter(|n| (1..=96).contains(n))
+            .ok_or_else(|| anyhow::anyhow!("bins per octave '{}' must be 1 to 96", n))?,
+        };
+        Ok(Transform::ConstantQ { bins_per_octave })
+      }
+      _ => Err(anyhow::anyhow!(
+        "unknown transform '{}', expected fft or cqt[:BINS_PER_OCTAVE]",
+        s
+      )),
+    }
+  }
+}
+
+/// The newest frames of a signal, kept in a ring so sliding on by a hop
+/// doesn't shift everything along
+pub struct History {
+  samples: Vec<f32>,
+  // where the next frame goes, over the oldest
+  next: usize,
+}
+
+impl History {
+  pub fn new(len: usize) -> Self {
+    Self {
+      samples: vec![0.0; len],
+      next: 0,
+    }
+  }
+
+  fn len(&self) -> usize {
+    self.samples.len()
+  }
+
+  /// Write `frames` over the oldest, filtering them in order
+  pub fn push(&mut self, frames: impl Iterator<Item = f32>, filters: &mut FilterChain) {
+    let len = self.len();
+    let start = self.next;
+    let mut count = 0;
+    for sample in frames.take(len) {
+      self.samples[self.next] = sample;
+      self.next = (self.next + 1) % len;
+      count += 1;
+    }
+    // the new frames may run on round the end
+    let wrapped = (start + count).saturating_sub(len);
+    filters.process(&mut self.samples[start..start + count - wrapped]);
+    filters.process(&mut self.samples[..wrapped]);
+  }
+
+  fn clear(&mut self) {
+    self.samples.fill(0.0);
+  }
+
+  /// The newest frames into `out`, oldest first and silence before the
+  /// signal starts if it is longer
+  pub fn copy_newest(&self, out: &mut [f32]) {
+    let (newer, older) = self.samples.split_at(self.next);
+    let from_newer = newer.len().min(out.len());
+    let from_older = older.len().min(out.len() - from_newer);
+    let silence = out.len() - from_newer - from_older;
+    out[..silence].fill(0.0);
+    out[silence..silence + from_older].copy_from_slice(&older[older.len() - from_older..]);
+    out[silence + from_older..].copy_from_slice(&newer[newer.len() - from_newer..]);
+  }
+}
+
+/// Spectra of one signal
+struct Analysis {
+  // the last window of filtered signal, or longer for the constant-q
+  history: History,
+  // amplitude of each bin, 1.0 being a full scale sine
+  amplitude: Vec<f32>,
+  // processed magnitudes (length = fft_len/2)
+  fft_output: Vec<f32>,
+  // amplitude of each constant-q bin, when the bars come from those
+  constant_q: Vec<f32>,
+  smoothed_fft: Vec<f32>,
+  // the configured filters, with this signal's state
+  filters: FilterChain,
+}
+
+impl Analysis {
+  fn new(history_len: usize, fft_len: usize, bar_count: usize, filters: FilterChain) -> Self {
+    Self {
+      history: History::new(history_len),
+      amplitude: vec![0.0; fft_len / 2],
+      fft_output: vec![0.0; fft_len / 2],
+      constant_q: Vec::new(),
+      smoothed_fft: vec![0.0; bar_count],
+      filters,
+    }
+  }
+
+  /// Slide the window on by the frames in `block`, filtering them on the way
+  /// in
+  fn push(&mut self, channel: Channel, block: &[f32], channels: usize) {
+    self.history.push(
+      block
+        .chunks_exact(channels)
+        .map(|frame| channel.sample(frame)),
+      &mut self.filters,
+    );
+  }
+}
+
+/// Short-time fourier transform over the followed channel of what it is
+/// given. Other channels, or the mono, mid and side mixes, are analysed from
+/// the first block after they are requested.
+///
+/// Audio can arrive in blocks of any size. Each signal keeps the last
+/// `fft_size` frames, and a frame is analysed every `hop_size` frames of new
+/// audio, zero padded out to `fft_size * zero_padding` for finer bins. With the
+/// constant-q transform the followed channel's bars come from that instead,
+/// over as much of the signal as its longest kernel needs, once its kernels
+/// for the rate are built.
 pub struct AudioProcessor {
   config: AudioConfig,
+  // frames between analyses, at most the window
+  hop: usize,
+  // fft length, the window plus its zero padding
+  fft_len: usize,
+  // frames taken in since the last analysis
+  pending: usize,
+  // analyses so far, which the constant-q paces its long kernels by
+  hops: u64,
+  // the followed channel's transform at the current rate, once built
+  constant_q: Option<ConstantQ>,
+  // builds and keeps the transform for each rate, when the bars come from it
+  kernels: Option<ConstantQCache>,
   fft: Arc<dyn RealToComplex<f32>>,
   window_function: Vec<f32>,
   // real input buffer for fft
   fft_real_input: Vec<f32>,
-  // complex output of fft (length = fft_size/2+1)
+  // complex output of fft (length = fft_len/2+1)
   fft_complex: Vec<Complex<f32>>,
   // scratch buffer used by the fft
   fft_scratch: Vec<Complex<f32>>,
-  // processed magnitudes (length = fft_size/2)
-  fft_output: Vec<f32>,
-  smoothed_fft: Vec<f32>,
+  // mono, mid, side, then one per channel of the latest samples, only those
+  // followed or asked for being analysed
+  analyses: Vec<Option<Analysis>>,
+  // slots analysed once the stream has them, the followed one first
+  requested: Vec<usize>,
+  // handed out for channels the stream doesn't have
+  silence: Vec<f32>,
   band_mapping: Vec<BandInfo>,
+  // bands over the constant-q bins, for the followed channel
+  constant_q_bands: Vec<BandInfo>,
   // last sampled rate, used to detect changes and trigger band recalculation
   sample_rate: f32,
-  // precomputed normalization factor (1/sqrt(N))
+  // precomputed factor from |X| to amplitude (2/(N * coherent gain))
   norm_factor: f32,
-  // precomputed gain and gamma combined
-  gain_gamma: f32,
+  // noise bandwidth of the window in bins, which bands are corrected by
+  enbw: f32,
+  // bar smoothing and decay, per analysis at the current rate and hop
+  smooth_factor: f32,
+  decay_factor: f32,
+  // keeps the loudest bins near the top of the display
+  agc: AutoGain,
 }
 
 impl AudioProcessor {
@@ -43,75 +386,241 @@ impl AudioProcessor {
   const MAG_BETA: f32 = 0.39782473;
 
   pub fn new(config: AudioConfig) -> Self {
+    let hop = config.hop_size.clamp(1, config.fft_size);
+    let fft_len = config.fft_size * config.zero_padding.max(1);
     let mut planner = RealFftPlanner::<f32>::new();
-    let r2c = planner.plan_fft_forward(config.fft_size);
+    let r2c = planner.plan_fft_forward(fft_len);
 
-    let window_function: Vec<f32> = hanning_iter(config.fft_size).map(|v| v as f32).collect();
+    let window_function = config.window.coefficients(config.fft_size);
 
     // allocate fft buffers once
     let fft_real_input = r2c.make_input_vec();
     let fft_complex = r2c.make_output_vec();
     let fft_scratch = r2c.make_scratch_vec();
 
-    // one-time precompute constants
-    let norm_factor = 1.0 / (config.fft_size as f32).sqrt();
-    let gain_gamma = 8.0f32.powf(0.6);
+    // one-time precompute constants, undoing the window's coherent gain so a
+    // tone reads the same whichever window is picked
+    let norm_factor = 2.0 / (config.fft_size as f32 * coherent_gain(&window_function));
+    // padding spreads everything over more, narrower bins
+    let enbw = enbw(&window_function) * (fft_len / config.fft_size) as f32;
+    let agc = AutoGain::new(config.agc);
+    // started on now if the rate is fixed, so it's ready for the first block
+    let kernels = match config.transform {
+      Transform::Fft => None,
+      Transform::ConstantQ { bins_per_octave } => {
+        let mut kernels = ConstantQCache::new(
+          bins_per_octave,
+          config.min_freq,
+          config.max_freq,
+          config.window,
+          hop,
+        );
+        if let Some(rate) = config.analysis_rate {
+          kernels.prepare(rate as f32);
+        }
+        Some(kernels)
+      }
+    };
 
-    let fft_size = config.fft_size;
     let bar_count = config.bar_count;
+    let followed = config.channel.slot();
     AudioProcessor {
       config,
+      hop,
+      fft_len,
+      pending: 0,
+      hops: 0,
+      constant_q: None,
+      kernels,
       fft: r2c,
       window_function,
       fft_real_input,
       fft_complex,
       fft_scratch,
-      fft_output: vec![0.0; fft_size / 2],
-      smoothed_fft: vec![0.0; bar_count],
-      band_mapping: Vec::with_capacity(bar_count),
+      analyses: Vec::new(),
+      requested: vec![followed],
+      silence: vec![0.0; (fft_len / 2).max(bar_count)],
+      band_mapping: Vec::new(),
+      constant_q_bands: Vec::new(),
       sample_rate: 0.0,
       norm_factor,
-      gain_gamma,
+      enbw,
+      smooth_factor: SMOOTH_FACTOR,
+      decay_factor: DECAY_FACTOR,
+      agc,
     }
   }
 
-  /// Process a block of samples at the given sample rate
-  pub fn process(&mut self, samples: &[f32], sample_rate: f32) {
-    // If the rate changed, rebuild our band map
+  /// Take in a block of new interleaved samples at the given sample rate,
+  /// analysing every hop it completes
+  pub fn process(&mut self, samples: &[f32], channels: u16, sample_rate: f32) {
+    // If the rate changed, rebuild our band map, filters and smoothing
     if (sample_rate - self.sample_rate).abs() > f32::EPSILON {
       self.sample_rate = sample_rate;
-      self.precalculate_bands(sample_rate);
+      // the fft's bars until the constant-q for this rate is built
+      if let Some(kernels) = &mut self.kernels {
+        if let Some(constant_q) = self.constant_q.take() {
+          kernels.put(constant_q);
+        }
+        kernels.prepare(sample_rate);
+      }
+      self.band_mapping = self.bands(None);
+      for analysis in self.analyses.iter_mut().flatten() {
+        analysis.history = History::new(self.config.fft_size);
+        analysis.constant_q.clear();
+        analysis.filters = FilterChain::new(&self.config.filters, sample_rate);
+      }
+      // tuned for a hop of 1024 frames at 48kHz, kept to the same time
+      // whatever the hop
+      let per_hop = (self.hop as f32 / sample_rate) / (1024.0 / 48_000.0);
+      self.smooth_factor = SMOOTH_FACTOR.powf(per_hop);
+      self.decay_factor = DECAY_FACTOR.powf(per_hop);
     }
 
     // nothing to do, just decay and finish up...
     if samples.is_empty() {
       self.decay();
+      // whatever comes next doesn't follow on from the last block
+      for analysis in self.analyses.iter_mut().flatten() {
+        analysis.history.clear();
+        analysis.filters.reset();
+      }
+      self.pending = 0;
+      return;
+    }
+
+    // a slot per channel on top of the mixes, analysed once followed or asked
+    // for and starting at rest
+    self
+      .analyses
+      .resize_with(3 + channels.max(1) as usize, || None);
+    self.install_constant_q();
+    for i in 0..self.requested.len() {
+      let slot = self.requested[i];
+      let history_len = self.history_len(slot);
+      if let Some(entry @ None) = self.analyses.get_mut(slot) {
+        *entry = Some(Analysis::new(
+          history_len,
+          self.fft_len,
+          self.config.bar_count,
+          FilterChain::new(&self.config.filters, sample_rate),
+        ));
+      }
+    }
+
+    // feed the block through a hop at a time, analysing whenever one is done
+    let channels = channels.max(1) as usize;
+    let mut rest = &samples[..samples.len() - samples.len() % channels];
+    while !rest.is_empty() {
+      let frames = (self.hop - self.pending).min(rest.len() / channels);
+      let (block, next) = rest.split_at(frames * channels);
+      for (slot, analysis) in self.analyses.iter_mut().enumerate() {
+        if let Some(analysis) = analysis {
+          analysis.push(Channel::from_slot(slot), block, channels);
+        }
+      }
+      rest = next;
+      self.pending += frames;
+
+      if self.pending == self.hop {
+        self.pending = 0;
+        // gain follows the followed channel alone, whatever else is shown
+        let followed = self.config.channel.slot();
+        let mut energy = 0.0;
+        for slot in 0..self.analyses.len() {
+          let bars = self.analyse(slot);
+          if slot == followed {
+            energy = bars;
+          }
+        }
+        if self.config.magnitude.level(1.0).is_some() {
+          self.agc.update(
+            energy.sqrt(),
+            Duration::from_secs_f32(self.hop as f32 / sample_rate),
+          );
+        }
+        self.hops += 1;
+      }
+    }
+  }
+
+  /// Switch the followed channel over to the constant-q once its kernels for
+  /// this rate are built
+  fn install_constant_q(&mut self) {
+    if self.constant_q.is_some() {
       return;
     }
+    let Some(constant_q) = self
+      .kernels
+      .as_mut()
+      .and_then(|kernels| kernels.take(self.sample_rate))
+    else {
+      return;
+    };
+    self.constant_q_bands = self.bands(Some(&constant_q));
+    self.constant_q = Some(constant_q);
+    // it looks further back than the window
+    let slot = self.config.channel.slot();
+    let history_len = self.history_len(slot);
+    if let Some(Some(analysis)) = self.analyses.get_mut(slot) {
+      analysis.history = History::new(history_len);
+    }
+  }
+
+  /// Frames kept of the signal at `slot`, enough for the window and, for the
+  /// followed channel, any constant-q
+  fn history_len(&self, slot: usize) -> usize {
+    match &self.constant_q {
+      Some(constant_q) if slot == self.config.channel.slot() => {
+        constant_q.len().max(self.config.fft_size)
+      }
+      _ => self.config.fft_size,
+    }
+  }
+
+  /// Whether the followed channel's bars come from the constant-q yet, the
+  /// fft standing in until its kernels are built
+  #[cfg_attr(not(test), allow(dead_code))]
+  pub fn constant_q_ready(&self) -> bool {
+    self.constant_q.is_some()
+  }
+
+  /// Gain currently applied to the magnitudes, as a factor, none when the
+  /// display is calibrated
+  pub fn gain(&self) -> Option<f32> {
+    self.config.magnitude.level(1.0).map(|_| self.agc.gain())
+  }
 
-    let fft_size = self.config.fft_size;
-    let half = fft_size / 2;
-    let count = fft_size.min(samples.len());
+  /// Fft the window of the signal at `slot`, returning the mean square of its
+  /// bars before gain, nothing if it isn't analysed
+  fn analyse(&mut self, slot: usize) -> f32 {
+    let Some(analysis) = self.analyses[slot].as_mut() else {
+      return 0.0;
+    };
+    let half = self.fft_len / 2;
 
     // zero-padded, windowed and dc-removed input
     // zero fill real buffer...
     self.fft_real_input.fill(0.0);
 
+    let count = self.config.fft_size;
+    analysis
+      .history
+      .copy_newest(&mut self.fft_real_input[..count]);
+
     // compute dc mean
     let mut sum = 0.0f32;
-    for &s in &samples[..count] {
+    for &s in &self.fft_real_input[..count] {
       sum += s;
     }
-    let mean = sum / (count as f32);
+    let mean = sum / (count.max(1) as f32);
 
     // apply window and dc removal
-    self
-      .fft_real_input
+    self.fft_real_input[..count]
       .iter_mut()
       .zip(self.window_function.iter())
-      .zip(samples.iter().take(count))
-      .for_each(|((out, w), s)| {
-        *out = (s - mean) * w;
+      .for_each(|(s, w)| {
+        *s = (*s - mean) * w;
       });
 
     // fft, use scratch for performance...
@@ -125,83 +634,447 @@ impl AudioProcessor {
       .expect("fft forward failed");
 
     // magnitude and scaling
+    let magnitude = self.config.magnitude;
+    let gain = self.agc.gain();
     for i in 0..half {
       let c = &self.fft_complex[i];
-      let re = c.re.abs();
-      let im = c.im.abs();
-      // https://en.wikipedia.org/wiki/Alpha_max_plus_beta_min_algorithm
-      let mag_approx = Self::MAG_ALPHA * re.max(im) + Self::MAG_BETA * re.min(im);
-      // apply normalization and gain/gamma in one go
-      let scaled = (mag_approx * self.norm_factor * self.gain_gamma).min(1.0);
-      self.fft_output[i] = scaled;
+      let mag = match magnitude {
+        Magnitude::Approximate => {
+          let re = c.re.abs();
+          let im = c.im.abs();
+          // https://en.wikipedia.org/wiki/Alpha_max_plus_beta_min_algorithm
+          Self::MAG_ALPHA * re.max(im) + Self::MAG_BETA * re.min(im)
+        }
+        _ => c.norm(),
+      };
+      let amplitude = mag * self.norm_factor;
+      analysis.amplitude[i] = amplitude;
+      analysis.fft_output[i] = magnitude.display(amplitude, gain);
     }
 
-    // update groupings
-    self.update_bands();
+    // update groupings, from the constant-q bins for the followed channel
+    let (bins, enbw, bands) = match &mut self.constant_q {
+      Some(constant_q) if slot == self.config.channel.slot() => {
+        constant_q.process(&analysis.history, self.hops, &mut analysis.constant_q);
+        (
+          &analysis.constant_q,
+          constant_q.enbw(),
+          &self.constant_q_bands,
+        )
+      }
+      _ => (&analysis.amplitude, self.enbw, &self.band_mapping),
+    };
+    update_bands(
+      bands,
+      bins,
+      enbw,
+      self.smooth_factor,
+      magnitude,
+      gain,
+      &mut analysis.smoothed_fft,
+    )
   }
 
-  fn precalculate_bands(&mut self, sample_rate: f32) {
-    self.band_mapping.clear();
+  /// Where each bar draws from among the fft's bins, or the constant-q's
+  fn bands(&self, constant_q: Option<&ConstantQ>) -> Vec<BandInfo> {
+    let scale = self.config.scale;
+    let edges = scale.edges(
+      self.config.bar_count,
+      self.config.min_freq,
+      self.config.max_freq,
+    );
+    let bin_width = self.sample_rate / self.fft_len as f32;
+    let position = |freq: f32| match constant_q {
+      Some(constant_q) => constant_q.position(freq),
+      None => freq / bin_width,
+    };
+    let last_bin = constant_q.map_or(self.fft_len / 2, ConstantQ::bins) - 1;
 
-    const F_MIN: f32 = 20.0;
-    const F_MAX: f32 = 20_000.0;
+    let mut bands = Vec::with_capacity(self.config.bar_count);
+    for edge in edges.windows(2).take(self.config.bar_count) {
+      let (freq_low, freq_high) = (edge[0], edge[1]);
+      // the band's share of the spectrum drawn straight between bins, so
+      // bands narrower than a bin still land somewhere of their own
+      let (low, high) = (position(freq_low), position(freq_high));
+      let bin_low = ((low - 1.0).ceil().max(0.0) as usize).min(last_bin + 1);
+      let bin_high = ((high + 1.0).floor().max(0.0) as usize).min(last_bin);
+      let weights = (bin_low..=bin_high)
+        .map(|k| hat_integral(high - k as f32) - hat_integral(low - k as f32))
+        .collect();
 
-    let fft_size = self.config.fft_size;
+      bands.push(BandInfo {
+        bin_low,
+        weights,
+        compensation: compensation(scale.centre(freq_low, freq_high)),
+      });
+    }
+    bands
+  }
 
-    for i in 0..self.config.bar_count {
-      let frac = i as f32 / (self.config.bar_count - 1) as f32;
-      let freq_center = F_MIN * (F_MAX / F_MIN).powf(frac);
-      let bandwidth_factor = 0.3 + 0.7 * frac;
-      let freq_low = freq_center / (1.0 + bandwidth_factor);
-      let freq_high = freq_center * (1.0 + bandwidth_factor);
+  fn decay(&mut self) {
+    for analysis in self.analyses.iter_mut().flatten() {
+      for val in &mut analysis.smoothed_fft {
+        *val *= self.decay_factor;
+      }
+    }
+  }
 
-      let bin_low = ((freq_low * fft_size as f32) / sample_rate).round() as usize;
-      let bin_high = ((freq_high * fft_size as f32) / sample_rate).round() as usize;
-      let bin_low = bin_low.min(fft_size / 2 - 2);
-      let bin_high = bin_high.max(bin_low + 1).min(fft_size / 2 - 1);
+  /// Analyse `channel` as well as the followed one, from the next block it
+  /// is in on
+  #[cfg_attr(not(test), allow(dead_code))]
+  pub fn request(&mut self, channel: Channel) {
+    let slot = channel.slot();
+    if !self.requested.contains(&slot) {
+      self.requested.push(slot);
+    }
+  }
 
-      let compensation = match i {
-        0..=7 => 0.2 + 0.3 * (i as f32 / 8.0),
-        8..=31 => 0.5 + 0.5 * ((i - 8) as f32 / 24.0),
-        _ => 1.0 + 1.0 * ((i - 32) as f32 / 32.0),
-      };
+  /// Smoothed bar levels of `channel`, flat for channels the stream lacks
+  /// or that weren't requested
+  pub fn spectrum(&self, channel: Channel) -> &[f32] {
+    match self.analysis(channel) {
+      Some(analysis) => &analysis.smoothed_fft,
+      None => &self.silence[..self.config.bar_count],
+    }
+  }
 
-      self.band_mapping.push(BandInfo {
-        bin_low,
-        bin_high,
-        compensation,
-      });
+  /// Raw magnitudes of `channel`, flat as for `spectrum`
+  pub fn fft_output(&self, channel: Channel) -> &[f32] {
+    match self.analysis(channel) {
+      Some(analysis) => &analysis.fft_output,
+      None => &self.silence[..self.fft_len / 2],
     }
   }
 
-  fn update_bands(&mut self) {
-    const SMOOTH_FACTOR: f32 = 0.85;
-    const ATTACK_FACTOR: f32 = 0.15;
+  fn analysis(&self, channel: Channel) -> Option<&Analysis> {
+    self.analyses.get(channel.slot()).and_then(Option::as_ref)
+  }
+}
 
-    for (i, band) in self.band_mapping.iter().enumerate() {
-      let avg = if band.bin_high > band.bin_low {
-        let sum: f32 = self.fft_output[band.bin_low..=band.bin_high].iter().sum();
-        sum / (band.bin_high - band.bin_low + 1) as f32
-      } else {
-        0.0
-      };
-      let compensated = (avg * band.compensation).min(1.0);
-      self.smoothed_fft[i] = self.smoothed_fft[i] * SMOOTH_FACTOR + compensated * ATTACK_FACTOR;
+/// Level of each band from the power of the bins it covers, the window's
+/// noise bandwidth taken off so tones and noise both read the same whatever
+/// the window.
+///
+/// Calibrated bars show the band's total, as an analyser does, so a full
+/// scale sine reads 0dbfs in its band. Otherwise bars show the average bin,
+/// tilted towards the treble so music looks even
+fn update_bands(
+  band_mapping: &[BandInfo],
+  bins: &[f32],
+  enbw: f32,
+  smooth_factor: f32,
+  magnitude: Magnitude,
+  gain: f32,
+  smoothed: &mut [f32],
+) -> f32 {
+  let mut energy = 0.0;
+  for (i, band) in band_mapping.iter().enumerate() {
+    let width: f32 = band.weights.iter().sum();
+    let value = if width > 0.0 {
+      let power = bins[band.bin_low..]
+        .iter()
+        .zip(&band.weights)
+        .map(|(a, w)| a * a * w)
+        .sum::<f32>()
+        / enbw;
+      match magnitude {
+        Magnitude::Decibels { .. } => magnitude.display(power.sqrt(), 1.0),
+        _ => {
+          let average = (power / width).sqrt();
+          let level = magnitude.level(average).unwrap_or_default() * band.compensation;
+          energy += level * level;
+          (magnitude.display(average, gain) * band.compensation).min(1.0)
+        }
+      }
+    } else {
+      0.0
+    };
+    smoothed[i] = smoothed[i] * smooth_factor + value * (1.0 - smooth_factor);
+  }
+  energy / band_mapping.len().max(1) as f32
+}
+
+/// Area under a unit triangle spanning -1..1, from -1 up to `x`. A bin's part
+/// of the spectrum between two points, in bins from its centre, is the
+/// difference of this at either end
+fn hat_integral(x: f32) -> f32 {
+  match x {
+    x if x <= -1.0 => 0.0,
+    x if x <= 0.0 => (x + 1.0) * (x + 1.0) / 2.0,
+    x if x <= 1.0 => 1.0 - (1.0 - x) * (1.0 - x) / 2.0,
+    _ => 1.0,
+  }
+}
+
+/// Treble boost at `freq`, following `COMPENSATION` on a log frequency axis
+fn compensation(freq: f32) -> f32 {
+  let (first, last) = (COMPENSATION[0], COMPENSATION[COMPENSATION.len() - 1]);
+  if freq <= first.0 {
+    return first.1;
+  }
+  COMPENSATION
+    .windows(2)
+    .find(|pair| freq <= pair[1].0)
+    .map(|pair| {
+      let (low, high) = (pair[0], pair[1]);
+      let t = (freq / low.0).ln() / (high.0 / low.0).ln();
+      low.1 + (high.1 - low.1) * t
+    })
+    .unwrap_or(last.1)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audio::test_config;
+  use crate::audio::window::WindowFunction;
+
+  const RATE: f32 = 48_000.0;
+
+  /// `frames` of a sine at `freq`
+  fn sine(freq: f32, amplitude: f32, frames: usize) -> Vec<f32> {
+    (0..frames)
+      .map(|n| amplitude * (2.0 * std::f32::consts::PI * freq * n as f32 / RATE).sin())
+      .collect()
+  }
+
+  fn peak(bars: &[f32]) -> f32 {
+    bars.iter().copied().fold(0.0, f32::max)
+  }
+
+  #[test]
+  fn history_keeps_the_newest_frames_across_the_wrap() {
+    let mut history = History::new(5);
+    let mut filters = FilterChain::new(&[], 48_000.0);
+    let mut signal: Vec<f32> = Vec::new();
+    let mut next = 1.0;
+    for frames in [3, 2, 4, 1, 5, 3] {
+      let block: Vec<f32> = (0..frames).map(|i| next + i as f32).collect();
+      next += frames as f32;
+      history.push(block.iter().copied(), &mut filters);
+      signal.extend(&block);
+
+      // longer than the history, silence comes first
+      let mut out = [f32::NAN; 7];
+      history.copy_newest(&mut out);
+      let kept = &signal[signal.len().saturating_sub(5)..];
+      let mut expected = vec![0.0; 7 - kept.len()];
+      expected.extend(kept);
+      assert_eq!(out[..], expected[..], "after {:?}", block);
+
+      let mut out = [0.0; 3];
+      history.copy_newest(&mut out);
+      assert_eq!(out[..], signal[signal.len() - 3..]);
     }
   }
 
-  fn decay(&mut self) {
-    const DECAY_FACTOR: f32 = 0.95;
-    for val in &mut self.smoothed_fft {
-      *val *= DECAY_FACTOR;
+  #[test]
+  fn other_channels_wait_until_requested() {
+    let config = test_config();
+    // the tone on the right only
+    let samples: Vec<f32> = sine(1000.0, 0.5, 20 * config.hop_size)
+      .into_iter()
+      .flat_map(|s| [0.0, s])
+      .collect();
+    let mut processor = AudioProcessor::new(config);
+
+    processor.process(&samples, 2, RATE);
+    assert!(peak(processor.spectrum(Channel::Mono)) > 0.0);
+    assert_eq!(peak(processor.spectrum(Channel::Index(1))), 0.0);
+
+    // reading doesn't ask for anything, requesting does
+    processor.process(&samples, 2, RATE);
+    assert_eq!(peak(processor.spectrum(Channel::Index(1))), 0.0);
+    processor.request(Channel::Index(1));
+    processor.request(Channel::Index(0));
+    processor.process(&samples, 2, RATE);
+    assert!(peak(processor.spectrum(Channel::Index(1))) > 0.0);
+    assert_eq!(peak(processor.spectrum(Channel::Index(0))), 0.0);
+  }
+
+  #[test]
+  fn gain_follows_the_followed_channel_only() {
+    let mut config = test_config();
+    config.channel = Channel::Index(0);
+    // quiet on the left, loud on the right
+    let left = sine(1000.0, 0.01, 40 * config.hop_size);
+    let right = sine(3000.0, 1.0, left.len());
+    let samples: Vec<f32> = left
+      .iter()
+      .zip(&right)
+      .flat_map(|(&l, &r)| [l, r])
+      .collect();
+
+    let mut alone = AudioProcessor::new(config.clone());
+    let mut with_right = AudioProcessor::new(config);
+    with_right.request(Channel::Index(1));
+    alone.process(&samples, 2, RATE);
+    with_right.process(&samples, 2, RATE);
+    assert_eq!(alone.gain(), with_right.gain());
+    assert!(peak(with_right.spectrum(Channel::Index(1))) > 0.0);
+  }
+
+  /// Index and level in db of the loudest bar
+  fn loudest(processor: &AudioProcessor, floor: f32, ceiling: f32) -> (usize, f32) {
+    let bars = processor.spectrum(Channel::Mono);
+    let index = (0..bars.len())
+      .max_by(|&a, &b| bars[a].total_cmp(&bars[b]))
+      .unwrap();
+    (index, floor + (ceiling - floor) * bars[index])
+  }
+
+  #[test]
+  fn analyses_once_a_hop_whatever_the_blocks() {
+    let config = test_config();
+    let hop = config.hop_size;
+    let samples = sine(1000.0, 0.5, 20 * hop + 100);
+
+    let mut whole = AudioProcessor::new(config.clone());
+    whole.process(&samples, 1, RATE);
+    assert_eq!(whole.hops, 20);
+
+    // blocks smaller, larger and across the hops
+    let mut pieces = AudioProcessor::new(config);
+    let mut rest = &samples[..];
+    for size in [1, hop - 1, hop, 3 * hop + 7, 5, 2 * hop - 12]
+      .iter()
+      .cycle()
+    {
+      if rest.is_empty() {
+        break;
+      }
+      let (block, next) = rest.split_at((*size).min(rest.len()));
+      pieces.process(block, 1, RATE);
+      rest = next;
     }
+    assert_eq!(pieces.hops, 20);
+    assert_eq!(
+      pieces.spectrum(Channel::Mono),
+      whole.spectrum(Channel::Mono)
+    );
   }
 
-  pub fn spectrum(&self) -> &[f32] {
-    &self.smoothed_fft
+  #[test]
+  fn zero_padding_keeps_a_tone_where_it_was() {
+    let mut config = test_config();
+    config.magnitude = Magnitude::Decibels {
+      floor: -90.0,
+      ceiling: 10.0,
+    };
+    let samples = sine(1000.0, 0.5, RATE as usize);
+
+    let mut read = Vec::new();
+    for padding in [1, 2, 4] {
+      config.zero_padding = padding;
+      let mut processor = AudioProcessor::new(config.clone());
+      processor.process(&samples, 1, RATE);
+      read.push(loudest(&processor, -90.0, 10.0));
+    }
+    let (bar, level) = read[0];
+    for &(padded_bar, padded_level) in &read[1..] {
+      assert_eq!(padded_bar, bar);
+      assert!(
+        (padded_level - level).abs() < 0.5,
+        "{}db padded against {}db",
+        padded_level,
+        level
+      );
+    }
   }
 
-  pub fn fft_output(&self) -> &[f32] {
-    &self.fft_output
+  #[test]
+  fn a_full_scale_sine_reads_0dbfs() {
+    let mut config = test_config();
+    // bars wide enough for the flat top's main lobe
+    config.fft_size = 4096;
+    config.hop_size = 1024;
+    config.bar_count = 32;
+    config.magnitude = Magnitude::Decibels {
+      floor: -90.0,
+      ceiling: 0.0,
+    };
+    let edges = config
+      .scale
+      .edges(config.bar_count, config.min_freq, config.max_freq);
+    let bar = edges.windows(2).position(|e| e[1] > 1000.0).unwrap();
+    let freq = config.scale.centre(edges[bar], edges[bar + 1]);
+    let samples = sine(freq, 1.0, RATE as usize);
+
+    for window in [
+      WindowFunction::Hann,
+      WindowFunction::BlackmanHarris,
+      WindowFunction::FlatTop,
+    ] {
+      for padding in [1, 4] {
+        config.window = window;
+        config.zero_padding = padding;
+        let mut processor = AudioProcessor::new(config.clone());
+        processor.process(&samples, 1, RATE);
+        let (loudest, level) = loudest(&processor, -90.0, 0.0);
+        assert_eq!(loudest, bar, "{:?} padded {}", window, padding);
+        assert!(
+          level > -0.5,
+          "{:?} padded {} read {}db",
+          window,
+          padding,
+          level
+        );
+        assert_eq!(processor.gain(), None);
+      }
+    }
+  }
+
+  #[test]
+  fn approximate_magnitudes_can_still_be_picked() {
+    let mut config = test_config();
+    config.magnitude = "approximate".parse().unwrap();
+    assert_eq!(config.magnitude, Magnitude::Approximate);
+
+    let edges = config
+      .scale
+      .edges(config.bar_count, config.min_freq, config.max_freq);
+    let bar = edges.windows(2).position(|e| e[1] > 1000.0).unwrap();
+    let mut processor = AudioProcessor::new(config);
+    processor.process(&sine(1000.0, 0.5, RATE as usize), 1, RATE);
+    let (loudest, level) = loudest(&processor, 0.0, 1.0);
+    assert_eq!(loudest, bar);
+    assert!(level > 0.0);
+    assert!(processor.gain().is_some());
+  }
+
+  #[test]
+  fn low_bars_share_bins_without_repeating_each_other() {
+    let mut config = test_config();
+    config.magnitude = Magnitude::Decibels {
+      floor: -150.0,
+      ceiling: 10.0,
+    };
+    let edges = config
+      .scale
+      .edges(config.bar_count, config.min_freq, config.max_freq);
+    let mut processor = AudioProcessor::new(config.clone());
+    processor.process(&sine(100.0, 0.5, RATE as usize), 1, RATE);
+
+    // several bars to a bin down here, each its own share of them
+    let bin_width = RATE / config.fft_size as f32;
+    let low = edges.iter().filter(|&&edge| edge < 200.0).count() - 1;
+    assert!(low > 10);
+    let bars = processor.spectrum(Channel::Mono);
+    for i in 0..low - 1 {
+      assert_ne!(bars[i], bars[i + 1], "bars {} and {}", i, i + 1);
+    }
+
+    // and between them the bars take all of the spectrum once over
+    for (band, edge) in processor.band_mapping.iter().zip(edges.windows(2)) {
+      let width: f32 = band.weights.iter().sum();
+      let expected = (edge[1] - edge[0]) / bin_width;
+      assert!(
+        (width - expected).abs() < expected * 1e-3,
+        "{}hz bar weighs {} bins, not {}",
+        edge[0],
+        width,
+        expected
+      );
+    }
   }
 }
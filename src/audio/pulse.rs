@@ -0,0 +1,349 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use libpulse_binding::callbacks::ListResult;
+use libpulse_binding::context::introspect::SourceInfo;
+use libpulse_binding::context::{self, Context, FlagSet};
+use libpulse_binding::def::BufferAttr;
+use libpulse_binding::mainloop::standard::{IterateResult, Mainloop};
+use libpulse_binding::operation::{self, Operation};
+use libpulse_binding::sample::{Format, Spec};
+use libpulse_binding::stream::Direction;
+use libpulse_simple_binding::Simple;
+
+use tokio::task;
+
+use tracing::info;
+
+use crate::audio::AudioConfig;
+use crate::audio::backend::{AudioBackend, AudioPacket, AudioSender, DeviceInfo};
+
+/// Captures the monitor source of the default sink through the pulseaudio
+/// simple api, which pipewire-pulse serves as well.
+///
+/// The default sink is polled while capturing, and the stream is reopened on
+/// the new sink's monitor whenever it changes. Naming a source pins capture to
+/// it instead.
+pub struct PulseBackend {
+  config: AudioConfig,
+  // source name, none follows the default sink's monitor
+  device: Option<String>,
+  stop: Arc<AtomicBool>,
+}
+
+impl PulseBackend {
+  pub fn new(config: AudioConfig, device: Option<String>, stop: Arc<AtomicBool>) -> Self {
+    Self {
+      config,
+      device,
+      stop,
+    }
+  }
+
+  /// Check that a pulse server is reachable
+  pub fn probe() -> Result<(), anyhow::Error> {
+    Introspector::connect().map(|_| ())
+  }
+
+  /// Every source on the server, sink monitors as loopback and the rest as
+  /// inputs
+  pub fn devices() -> Result<Vec<DeviceInfo>, anyhow::Error> {
+    Introspector::connect()?.sources()
+  }
+}
+
+#[async_trait]
+impl AudioBackend for PulseBackend {
+  async fn run(self: Box<Self>, tx: AudioSender) -> Result<(), anyhow::Error> {
+    task::spawn_blocking(move || capture_loop(self.config, self.device, self.stop, tx)).await??;
+    Ok(())
+  }
+}
+
+/// The source we are recording and the format it runs at
+#[derive(PartialEq)]
+struct SourceSpec {
+  name: String,
+  sample_rate: u32,
+  channels: u8,
+}
+
+fn capture_loop(
+  config: AudioConfig,
+  device: Option<String>,
+  stop: Arc<AtomicBool>,
+  mut tx: AudioSender,
+) -> Result<(), anyhow::Error> {
+  // how often we ask the server which sink is the default
+  const FOLLOW_INTERVAL: Duration = Duration::from_secs(1);
+  // 10ms fragments keep the stop flag responsive
+  const FRAGMENT_MS: u32 = 10;
+
+  let mut introspector = Introspector::connect()?;
+
+  // kept across reopens so sequence numbers carry on
+  let mut packet = AudioPacket::default();
+  let mut bytes: Vec<u8> = Vec::new();
+  let mut reopened = false;
+
+  while !stop.load(Ordering::Relaxed) {
+    let source = match &device {
+      Some(name) => introspector.source(name)?,
+      None => introspector.default_monitor()?,
+    };
+    let channels = source.channels as usize;
+    let frames = (source.sample_rate * FRAGMENT_MS / 1000) as usize;
+    // the server holds at most the capture buffer, so a stall drops audio
+    // rather than handing us seconds of backlog
+    let buffer = config.buffer_size.max(frames * 4);
+    let stream = record(&source, frames, buffer)?;
+
+    info!(
+      "pulse capture started on {} ({} Hz, {} channels)...",
+      source.name, source.sample_rate, source.channels
+    );
+
+    bytes.resize(frames * channels * size_of::<f32>(), 0);
+    packet.sample_rate = source.sample_rate as f32;
+    packet.channels = source.channels as u16;
+    // whatever played while the stream was closed is gone
+    packet.discontinuity = std::mem::replace(&mut reopened, true);
+    let mut last_follow = Instant::now();
+
+    while !stop.load(Ordering::Relaxed) {
+      stream
+        .read(&mut bytes)
+        .map_err(|e| anyhow::anyhow!("pulse read failed - {}", e))?;
+      packet.samples.clear();
+      packet.samples.extend(
+        bytes
+          .chunks_exact(size_of::<f32>())
+          .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]])),
+      );
+      packet.is_silent = packet.samples.iter().all(|&s| s == 0.0);
+      // the simple api blocks until the fragment is complete
+      packet.timestamp = Instant::now() - packet.duration();
+
+      tx.write(&mut packet);
+
+      // a named source is never followed
+      if device.is_none() && last_follow.elapsed() >= FOLLOW_INTERVAL {
+        last_follow = Instant::now();
+        if introspector.default_monitor()? != source {
+          info!("default sink changed, reopening capture...");
+          break;
+        }
+      }
+    }
+  }
+
+  info!("pulse capture stopped...");
+  Ok(())
+}
+
+/// Open a blocking record stream on `source`, delivering `fragment_frames` at
+/// a time and buffering at most `buffer_frames` server side
+fn record(
+  source: &SourceSpec,
+  fragment_frames: usize,
+  buffer_frames: usize,
+) -> Result<Simple, anyhow::Error> {
+  let spec = Spec {
+    format: Format::FLOAT32NE,
+    rate: source.sample_rate,
+    channels: source.channels,
+  };
+  let frame_bytes = spec.frame_size();
+  let attr = BufferAttr {
+    maxlength: (buffer_frames * frame_bytes) as u32,
+    tlength: u32::MAX,
+    prebuf: u32::MAX,
+    minreq: u32::MAX,
+    fragsize: (fragment_frames * frame_bytes) as u32,
+  };
+  Simple::new(
+    None,
+    "field",
+    Direction::Record,
+    Some(&source.name),
+    "visualiser capture",
+    &spec,
+    None,
+    Some(&attr),
+  )
+  .map_err(|e| anyhow::anyhow!("failed to open pulse source {} - {}", source.name, e))
+}
+
+/// Synchronous wrapper over the async context api, used for server queries
+struct Introspector {
+  // dropped before the mainloop it runs on
+  context: Context,
+  mainloop: Mainloop,
+}
+
+impl Introspector {
+  fn connect() -> Result<Self, anyhow::Error> {
+    let mainloop = Mainloop::new().ok_or_else(|| anyhow::anyhow!("pa_mainloop_new failed"))?;
+    let mut context =
+      Context::new(&mainloop, "field").ok_or_else(|| anyhow::anyhow!("pa_context_new failed"))?;
+    context
+      .connect(None, FlagSet::NOFLAGS, None)
+      .map_err(|e| anyhow::anyhow!("failed to connect to the pulse server - {}", e))?;
+    // from here on drop disconnects
+    let mut this = Self { context, mainloop };
+
+    loop {
+      match this.context.get_state() {
+        context::State::Ready => break,
+        context::State::Failed | context::State::Terminated => {
+          return Err(anyhow::anyhow!("pulse server connection failed"));
+        }
+        _ => this.iterate()?,
+      }
+    }
+    Ok(this)
+  }
+
+  fn iterate(&mut self) -> Result<(), anyhow::Error> {
+    match self.mainloop.iterate(true) {
+      IterateResult::Success(_) => Ok(()),
+      IterateResult::Quit(_) | IterateResult::Err(_) => {
+        Err(anyhow::anyhow!("pulse mainloop failed"))
+      }
+    }
+  }
+
+  /// Drive the mainloop until `op` completes
+  fn wait<F: ?Sized>(&mut self, op: Operation<F>) -> Result<(), anyhow::Error> {
+    while op.get_state() == operation::State::Running {
+      self.iterate()?;
+    }
+    Ok(())
+  }
+
+  /// Default sink and source names, either of which the server may lack
+  fn defaults(&mut self) -> Result<(Option<String>, Option<String>), anyhow::Error> {
+    let defaults = Rc::new(RefCell::new((None, None)));
+    let out = Rc::clone(&defaults);
+    let op = self.context.introspect().get_server_info(move |info| {
+      *out.borrow_mut() = (
+        info.default_sink_name.as_deref().map(str::to_owned),
+        info.default_source_name.as_deref().map(str::to_owned),
+      );
+    });
+    self.wait(op)?;
+    Ok(defaults.take())
+  }
+
+  /// Monitor source of the current default sink
+  fn default_monitor(&mut self) -> Result<SourceSpec, anyhow::Error> {
+    let (sink, _) = self.defaults()?;
+    let sink = sink.ok_or_else(|| anyhow::anyhow!("pulse server has no default sink"))?;
+    let monitor = self
+      .monitor_of(&sink)?
+      .ok_or_else(|| anyhow::anyhow!("pulse sink {} has no monitor source", sink))?;
+    self.source(&monitor)
+  }
+
+  /// Name of the source monitoring `sink`, as the server reports it rather
+  /// than going by the usual naming
+  fn monitor_of(&mut self, sink: &str) -> Result<Option<String>, anyhow::Error> {
+    let monitor = Rc::new(RefCell::new(None));
+    let out = Rc::clone(&monitor);
+    let op = self
+      .context
+      .introspect()
+      .get_sink_info_by_name(sink, move |result| {
+        if let ListResult::Item(info) = result {
+          *out.borrow_mut() = info.monitor_source_name.as_deref().map(str::to_owned);
+        }
+      });
+    self.wait(op)?;
+    Ok(monitor.take())
+  }
+
+  fn source(&mut self, name: &str) -> Result<SourceSpec, anyhow::Error> {
+    let spec = Rc::new(RefCell::new(None));
+    let out = Rc::clone(&spec);
+    let op = self
+      .context
+      .introspect()
+      .get_source_info_by_name(name, move |result| {
+        if let ListResult::Item(info) = result {
+          *out.borrow_mut() = Some((info.sample_spec.rate, info.sample_spec.channels));
+        }
+      });
+    self.wait(op)?;
+    let (sample_rate, channels) = spec
+      .take()
+      .ok_or_else(|| anyhow::anyhow!("pulse source {} not found", name))?;
+
+    Ok(SourceSpec {
+      name: name.to_owned(),
+      sample_rate,
+      channels,
+    })
+  }
+
+  fn sources(&mut self) -> Result<Vec<DeviceInfo>, anyhow::Error> {
+    let (default_sink, default_source) = self.defaults()?;
+    // what we would capture without a device, and what pulse would record from
+    let default_monitor = match default_sink {
+      Some(sink) => self.monitor_of(&sink)?,
+      None => None,
+    };
+
+    let devices = Rc::new(RefCell::new(Vec::new()));
+    let out = Rc::clone(&devices);
+    let op = self
+      .context
+      .introspect()
+      .get_source_info_list(move |result| {
+        if let ListResult::Item(info) = result
+          && let Some(device) = device_info(info, &default_monitor, &default_source)
+        {
+          out.borrow_mut().push(device);
+        }
+      });
+    self.wait(op)?;
+    Ok(devices.take())
+  }
+}
+
+impl Drop for Introspector {
+  fn drop(&mut self) {
+    self.context.disconnect();
+  }
+}
+
+/// Listing entry for a source, marked default if it is the one we or pulse
+/// would pick
+fn device_info(
+  info: &SourceInfo,
+  default_monitor: &Option<String>,
+  default_source: &Option<String>,
+) -> Option<DeviceInfo> {
+  let id = info.name.as_deref()?.to_owned();
+  let loopback = info.monitor_of_sink.is_some();
+  let default = if loopback {
+    default_monitor
+  } else {
+    default_source
+  };
+  Some(DeviceInfo {
+    name: info
+      .description
+      .as_deref()
+      .map_or_else(|| id.clone(), str::to_owned),
+    is_default: default.as_ref() == Some(&id),
+    id,
+    loopback,
+    sample_rate: Some(info.sample_spec.rate),
+    channels: Some(info.sample_spec.channels as u16),
+  })
+}
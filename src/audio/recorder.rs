@@ -0,0 +1,293 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use chrono::Local;
+
+use clap::Args;
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use tracing::{error, info};
+
+use crate::audio::ring::{RingFormat, RingReader};
+
+// how often the ring is drained, well inside its history
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// frames copied out of the ring at a time
+const CHUNK_FRAMES: usize = 256;
+
+/// Where and when the capture is recorded
+#[derive(Args, Clone)]
+pub struct RecordOptions {
+  /// Record the capture from the start, at the rate it was captured at, R
+  /// toggles recording at any time
+  #[arg(long)]
+  pub record: bool,
+  /// Directory recordings are saved to
+  #[arg(long, value_name = "DIR", default_value = ".")]
+  pub record_dir: PathBuf,
+  /// Start a new recording file every SECS seconds
+  #[arg(long, value_name = "SECS")]
+  pub record_segment: Option<u64>,
+}
+
+/// Writes the captured stream to wav files from its own thread, reading the
+/// sample ring like the analysis does so capture never waits on the disk.
+///
+/// Samples are stored as 32-bit float at the rate and layout they were
+/// captured at, before any resampling for the analysis, so playing a recording
+/// back through the file backend reproduces the visuals.
+/// A new file is started whenever the format changes, and every `segment` if
+/// one is set.
+pub struct Recorder {
+  recording: Arc<AtomicBool>,
+  quit: Arc<AtomicBool>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+  pub fn new(ring: RingReader, options: &RecordOptions) -> Result<Self, anyhow::Error> {
+    let dir = options.record_dir.clone();
+    let segment = options
+      .record_segment
+      .filter(|&secs| secs > 0)
+      .map(Duration::from_secs);
+    let recording = Arc::new(AtomicBool::new(options.record));
+    let quit = Arc::new(AtomicBool::new(false));
+    let handle = std::thread::Builder::new().name("recorder".into()).spawn({
+      let recording = Arc::clone(&recording);
+      let quit = Arc::clone(&quit);
+      move || record_loop(ring, dir, segment, recording, quit)
+    })?;
+    Ok(Self {
+      recording,
+      quit,
+      handle: Some(handle),
+    })
+  }
+
+  pub fn is_recording(&self) -> bool {
+    self.recording.load(Ordering::Relaxed)
+  }
+
+  /// Start or stop recording, returning whether we now are
+  pub fn toggle(&self) -> bool {
+    !self.recording.fetch_xor(true, Ordering::Relaxed)
+  }
+}
+
+impl Drop for Recorder {
+  fn drop(&mut self) {
+    // let the thread finish off the current file
+    self.quit.store(true, Ordering::Relaxed);
+    if let Some(handle) = self.handle.take() {
+      let _ = handle.join();
+    }
+  }
+}
+
+fn record_loop(
+  mut ring: RingReader,
+  dir: PathBuf,
+  segment: Option<Duration>,
+  recording: Arc<AtomicBool>,
+  quit: Arc<AtomicBool>,
+) {
+  let mut chunk = Vec::new();
+  let mut take: Option<Take> = None;
+
+  loop {
+    // read the flags once so a stop can't land halfway through a drain
+    let quitting = quit.load(Ordering::Relaxed);
+    let recording_now = recording.load(Ordering::Relaxed);
+
+    if recording_now || take.is_some() {
+      // a take being stopped gets the last few frames too
+      let flush = quitting || !recording_now;
+      if let Err(e) = drain(&mut ring, &dir, segment, flush, &mut chunk, &mut take) {
+        error!("recording failed - {}", e);
+        recording.store(false, Ordering::Relaxed);
+      }
+    }
+
+    if !recording_now || quitting {
+      if let Some(take) = take.take() {
+        take.finish();
+      }
+      // whatever arrives while we aren't recording is never wanted
+      ring.skip_to_end();
+    }
+    if quitting {
+      break;
+    }
+    std::thread::sleep(POLL_INTERVAL);
+  }
+}
+
+/// Write everything new in the ring to the current take, starting new ones as
+/// needed. Whole chunks only unless flushing, when the remainder follows a
+/// frame at a time
+fn drain(
+  ring: &mut RingReader,
+  dir: &Path,
+  segment: Option<Duration>,
+  flush: bool,
+  chunk: &mut Vec<f32>,
+  take: &mut Option<Take>,
+) -> Result<(), anyhow::Error> {
+  let mut frames = CHUNK_FRAMES;
+  loop {
+    let Some(read) = ring.next(frames, frames, chunk) else {
+      if flush && frames > 1 {
+        frames = 1;
+        continue;
+      }
+      return Ok(());
+    };
+    let full = |take: &Take| {
+      segment.is_some_and(|segment| {
+        take.frames as f64 >= segment.as_secs_f64() * take.format.sample_rate as f64
+      })
+    };
+    if take
+      .as_ref()
+      .is_none_or(|take| take.format != read.format || full(take))
+    {
+      if let Some(take) = take.take() {
+        take.finish();
+      }
+      *take = Some(Take::create(dir, read.format)?);
+    }
+
+    if let Some(take) = take {
+      take.write(chunk)?;
+    }
+  }
+}
+
+/// One wav file being written
+struct Take {
+  path: PathBuf,
+  format: RingFormat,
+  writer: WavWriter<BufWriter<File>>,
+  frames: u64,
+}
+
+impl Take {
+  fn create(dir: &Path, format: RingFormat) -> Result<Self, anyhow::Error> {
+    std::fs::create_dir_all(dir)?;
+    let stamp = Local::now().format("%Y%m%d-%H%M%S");
+    // segments can roll over within a second of each other
+    let path = (1..)
+      .map(|n| match n {
+        1 => dir.join(format!("field-{}.wav", stamp)),
+        n => dir.join(format!("field-{}-{}.wav", stamp, n)),
+      })
+      .find(|path| !path.exists())
+      .unwrap_or_else(|| dir.join(format!("field-{}.wav", stamp)));
+
+    let spec = WavSpec {
+      channels: format.channels,
+      sample_rate: format.sample_rate.round() as u32,
+      bits_per_sample: 32,
+      sample_format: SampleFormat::Float,
+    };
+    let writer = WavWriter::create(&path, spec)?;
+    info!(
+      "recording {} Hz, {} channels to {}...",
+      spec.sample_rate,
+      spec.channels,
+      path.display()
+    );
+    Ok(Self {
+      path,
+      format,
+      writer,
+      frames: 0,
+    })
+  }
+
+  fn write(&mut self, samples: &[f32]) -> Result<(), anyhow::Error> {
+    for &s in samples {
+      self.writer.write_sample(s)?;
+    }
+    self.frames += (samples.len() / self.format.channels.max(1) as usize) as u64;
+    Ok(())
+  }
+
+  /// Fix up the header so the file plays, logging rather than failing
+  fn finish(self) {
+    let seconds = self.frames as f64 / self.format.sample_rate as f64;
+    match self.writer.finalize() {
+      Ok(()) => info!("saved {:.1}s to {}...", seconds, self.path.display()),
+      Err(e) => error!("could not finish {} - {}", self.path.display(), e),
+    }
+  }
+}
+
+#[cfg(all(test, feature = "generator"))]
+mod tests {
+  use super::*;
+  use crate::audio::backend::{AudioBackend, AudioSender};
+  use crate::audio::generator::{Signal, SignalGeneratorBackend};
+  use crate::audio::ring::SampleRing;
+  use crate::audio::test_config;
+
+  #[tokio::test]
+  async fn records_the_capture_at_its_own_rate() {
+    let dir = std::env::temp_dir().join(format!("field-record-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let config = test_config();
+    let ring = SampleRing::new(config.history_size);
+    let capture = SampleRing::new(config.history_size);
+    let options = RecordOptions {
+      record: true,
+      record_dir: dir.clone(),
+      record_segment: None,
+    };
+    let recorder = Recorder::new(capture.reader(), &options).unwrap();
+
+    // captured at 44.1kHz, analysed at 48kHz
+    let stop = Arc::new(AtomicBool::new(false));
+    let generator = Box::new(SignalGeneratorBackend::new(
+      config.clone(),
+      Signal::Sine(1000.0),
+      0.5,
+      44_100,
+      2,
+      Arc::clone(&stop),
+    ));
+    let tx =
+      AudioSender::new(ring.writer(), config.analysis_rate).with_capture(Some(capture.writer()));
+    let run = tokio::spawn(generator.run(tx));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    stop.store(true, Ordering::Relaxed);
+    run.await.unwrap().unwrap();
+    let written = capture.reader().written();
+    // finishes off the take
+    drop(recorder);
+
+    let files: Vec<_> = std::fs::read_dir(&dir)
+      .unwrap()
+      .map(|entry| entry.unwrap().path())
+      .collect();
+    let wav = hound::WavReader::open(&files[0]).unwrap();
+    let (spec, samples) = (wav.spec(), wav.len() as u64);
+    drop(wav);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(files.len(), 1);
+    assert_eq!(spec.sample_rate, 44_100);
+    assert_eq!(spec.channels, 2);
+    assert_eq!(spec.bits_per_sample, 32);
+    assert_eq!(spec.sample_format, SampleFormat::Float);
+    // every sample captured, a couple of hundred ms' worth at least
+    assert_eq!(samples, written);
+    assert!(samples >= 2 * 44_100 / 5, "{} samples", samples);
+  }
+}
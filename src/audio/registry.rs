@@ -0,0 +1,492 @@
+#[cfg(any(feature = "file", feature = "pipe"))]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+#[cfg(feature = "net")]
+use std::time::Duration;
+
+use clap::Args;
+
+use tracing::info;
+
+use crate::audio::AudioConfig;
+use crate::audio::backend::{AudioBackend, DeviceInfo};
+#[cfg(any(feature = "pipe", feature = "net"))]
+use crate::audio::format::SampleFormat;
+#[cfg(feature = "generator")]
+use crate::audio::generator::Signal;
+#[cfg(feature = "mix")]
+use crate::audio::mixer::{MixMode, MixSource};
+
+/// Backend selection and the options each backend reads, most of which only
+/// matter to one of them
+#[derive(Args, Clone)]
+pub struct BackendOptions {
+  /// Backend to use (see --list-backends), otherwise the first one that can
+  /// start is picked
+  #[arg(long, value_name = "NAME")]
+  pub backend: Option<String>,
+  /// Device id to capture from (see --list-devices), otherwise the backend's
+  /// default
+  #[arg(long, value_name = "ID")]
+  pub device: Option<String>,
+  /// Wav file to play instead of capturing live audio
+  #[cfg(feature = "file")]
+  pub file: Option<PathBuf>,
+  /// Restart the file from the beginning when it ends
+  #[cfg(feature = "file")]
+  #[arg(long = "loop", requires = "file")]
+  pub looping: bool,
+  /// Read raw interleaved pcm from a fifo, or `-` for stdin
+  #[cfg(feature = "pipe")]
+  #[arg(long, value_name = "PATH")]
+  pub pcm: Option<PathBuf>,
+  /// Listen for pcm over udp on HOST:PORT, or on a port of every interface
+  #[cfg(feature = "net")]
+  #[arg(long, value_name = "ADDR")]
+  pub listen: Option<String>,
+  /// Expect rtp on the --listen port rather than bare pcm datagrams
+  #[cfg(feature = "net")]
+  #[arg(long, requires = "listen")]
+  pub rtp: bool,
+  /// How long rtp packets are held to put them back in order, in ms
+  #[cfg(feature = "net")]
+  #[arg(long, value_name = "MS", default_value_t = 40)]
+  pub jitter_buffer: u64,
+  /// Sample encoding of pcm and network input (s16, s24, s24_32, s32, f32 or
+  /// f64, with an le/be suffix), rtp's L16 and L24 being s16be and s24be
+  #[cfg(any(feature = "pipe", feature = "net"))]
+  #[arg(long, default_value = "f32le")]
+  pub format: SampleFormat,
+  /// Generate a test signal: sine:HZ, tones:HZ,HZ,..., sweep:LOW-HIGH:SECS,
+  /// white, pink, impulse:HZ or silence
+  #[cfg(feature = "generator")]
+  #[arg(long, value_name = "SIGNAL")]
+  pub generate: Option<Signal>,
+  /// Peak level of the generated signal, 1.0 being full scale
+  #[cfg(feature = "generator")]
+  #[arg(long, default_value_t = 0.5)]
+  pub amplitude: f32,
+  /// Alsa pcm device to capture from, e.g. `hw:Loopback,1`, --device takes
+  /// precedence
+  #[cfg(all(target_os = "linux", feature = "alsa"))]
+  #[arg(long, value_name = "DEVICE", default_value = "default")]
+  pub alsa_device: String,
+  /// Jack output ports to connect to, one per channel
+  #[cfg(feature = "jack")]
+  #[arg(long, value_name = "PORT", value_delimiter = ',')]
+  pub jack_connect: Vec<String>,
+  /// Mix another backend in, as NAME[@DEVICE][*GAIN], e.g. `--mix pulse
+  /// --mix pulse@mic*0.5`. Sources share every other option
+  #[cfg(feature = "mix")]
+  #[arg(long = "mix", value_name = "SOURCE")]
+  pub mix: Vec<MixSource>,
+  /// How mixed sources are combined, sum or split into separate channels
+  #[cfg(feature = "mix")]
+  #[arg(long, value_name = "MODE", default_value = "sum", requires = "mix")]
+  pub mix_mode: MixMode,
+  /// Sample rate for backends that let us choose
+  #[arg(long, default_value_t = 48_000)]
+  pub rate: u32,
+  /// Channel count for backends that let us choose
+  #[arg(long, default_value_t = 2)]
+  pub channels: u16,
+}
+
+impl BackendOptions {
+  /// A file, pipe, listen address and signal each pick their own backend, so
+  /// only one may be given unless they are being mixed
+  pub fn check_inputs(&self) -> Result<(), String> {
+    #[cfg(feature = "mix")]
+    if !self.mix.is_empty() {
+      return Ok(());
+    }
+    let inputs: [(&str, bool); _] = [
+      #[cfg(feature = "file")]
+      ("[FILE]", self.file.is_some()),
+      #[cfg(feature = "pipe")]
+      ("--pcm <PATH>", self.pcm.is_some()),
+      #[cfg(feature = "net")]
+      ("--listen <ADDR>", self.listen.is_some()),
+      #[cfg(feature = "generator")]
+      ("--generate <SIGNAL>", self.generate.is_some()),
+    ];
+    let given: Vec<&str> = inputs
+      .into_iter()
+      .filter(|(_, given)| *given)
+      .map(|(name, _)| name)
+      .collect();
+    match given.as_slice() {
+      [first, second, ..] => Err(format!(
+        "the argument '{}' cannot be used with '{}' unless they are mixed with --mix",
+        first, second
+      )),
+      _ => Ok(()),
+    }
+  }
+}
+
+type CreateFn =
+  fn(&BackendOptions, AudioConfig, Arc<AtomicBool>) -> Result<Box<dyn AudioBackend>, anyhow::Error>;
+type DevicesFn = fn() -> Result<Vec<DeviceInfo>, anyhow::Error>;
+
+pub struct BackendEntry {
+  pub name: &'static str,
+  pub description: &'static str,
+  create: CreateFn,
+  devices: DevicesFn,
+}
+
+impl BackendEntry {
+  pub fn create(
+    &self,
+    options: &BackendOptions,
+    config: AudioConfig,
+    stop: Arc<AtomicBool>,
+  ) -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+    (self.create)(options, config, stop)
+  }
+
+  /// Devices this backend can capture from, empty for sources that don't have
+  /// any
+  pub fn devices(&self) -> Result<Vec<DeviceInfo>, anyhow::Error> {
+    (self.devices)()
+  }
+
+  /// An entry outside the registry, without devices
+  #[cfg(test)]
+  pub const fn new(name: &'static str, create: CreateFn) -> Self {
+    Self {
+      name,
+      description: name,
+      create,
+      devices: || Ok(Vec::new()),
+    }
+  }
+}
+
+/// Every backend compiled into this build, in fallback order.
+///
+/// Sources that need an explicit input come first and refuse to start without
+/// it, so passing a file or signal is enough to select them.
+pub const BACKENDS: &[BackendEntry] = &[
+  #[cfg(feature = "mix")]
+  BackendEntry {
+    name: "mix",
+    description: "several backends mixed together",
+    create: create_mix,
+    devices: no_devices,
+  },
+  #[cfg(feature = "file")]
+  BackendEntry {
+    name: "file",
+    description: "wav file played at real-time pace",
+    create: create_file,
+    devices: no_devices,
+  },
+  #[cfg(feature = "pipe")]
+  BackendEntry {
+    name: "pipe",
+    description: "raw pcm from stdin or a fifo",
+    create: create_pipe,
+    devices: no_devices,
+  },
+  #[cfg(feature = "net")]
+  BackendEntry {
+    name: "net",
+    description: "pcm over udp, bare or rtp",
+    create: create_net,
+    devices: no_devices,
+  },
+  #[cfg(feature = "generator")]
+  BackendEntry {
+    name: "generator",
+    description: "synthetic test signals",
+    create: create_generator,
+    devices: no_devices,
+  },
+  #[cfg(all(target_os = "windows", feature = "wasapi"))]
+  BackendEntry {
+    name: "wasapi",
+    description: "loopback of the default output device",
+    create: create_wasapi,
+    devices: crate::audio::wasapi::WasapiBackend::devices,
+  },
+  #[cfg(all(target_os = "linux", feature = "pulse"))]
+  BackendEntry {
+    name: "pulse",
+    description: "monitor of the default pulseaudio/pipewire sink",
+    create: create_pulse,
+    devices: crate::audio::pulse::PulseBackend::devices,
+  },
+  #[cfg(feature = "jack")]
+  BackendEntry {
+    name: "jack",
+    description: "jack client with one input port per channel",
+    create: create_jack,
+    devices: crate::audio::jack::JackBackend::devices,
+  },
+  #[cfg(all(target_os = "linux", feature = "alsa"))]
+  BackendEntry {
+    name: "alsa",
+    description: "alsa pcm capture device",
+    create: create_alsa,
+    devices: crate::audio::alsa::AlsaBackend::devices,
+  },
+];
+
+/// Create the backend named in `options`, or the first one in `BACKENDS` that
+/// can start, returning its entry alongside it
+pub fn create(
+  options: &BackendOptions,
+  config: AudioConfig,
+  stop: Arc<AtomicBool>,
+) -> Result<(&'static BackendEntry, Box<dyn AudioBackend>), anyhow::Error> {
+  if let Some(name) = &options.backend {
+    let entry = BACKENDS
+      .iter()
+      .find(|entry| entry.name.eq_ignore_ascii_case(name))
+      .ok_or_else(|| {
+        let names: Vec<_> = BACKENDS.iter().map(|entry| entry.name).collect();
+        anyhow::anyhow!(
+          "unknown backend '{}', this build has: {}",
+          name,
+          names.join(", ")
+        )
+      })?;
+    let backend = entry.create(options, config, stop)?;
+    return Ok((entry, backend));
+  }
+
+  for entry in BACKENDS {
+    match entry.create(options, config.clone(), Arc::clone(&stop)) {
+      Ok(backend) => return Ok((entry, backend)),
+      Err(e) => info!("skipping {} backend - {}", entry.name, e),
+    }
+  }
+  Err(anyhow::anyhow!("no audio backend could be started"))
+}
+
+#[cfg(any(
+  feature = "file",
+  feature = "pipe",
+  feature = "net",
+  feature = "generator",
+  feature = "mix"
+))]
+fn no_devices() -> Result<Vec<DeviceInfo>, anyhow::Error> {
+  Ok(Vec::new())
+}
+
+/// Fail unless `--device` is one that `devices` lists, so fallback moves on
+/// rather than picking a backend that can't open it. Alsa takes any pcm name
+/// and probes it instead
+#[cfg(any(
+  all(target_os = "windows", feature = "wasapi"),
+  all(target_os = "linux", feature = "pulse"),
+  feature = "jack"
+))]
+fn check_device(options: &BackendOptions, devices: DevicesFn) -> Result<(), anyhow::Error> {
+  let Some(id) = &options.device else {
+    return Ok(());
+  };
+  if devices()?.iter().any(|device| &device.id == id) {
+    Ok(())
+  } else {
+    Err(anyhow::anyhow!("no device '{}', see --list-devices", id))
+  }
+}
+
+#[cfg(feature = "mix")]
+fn create_mix(
+  options: &BackendOptions,
+  config: AudioConfig,
+  stop: Arc<AtomicBool>,
+) -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+  use crate::audio::mixer::MixerBackend;
+
+  if options.mix.is_empty() {
+    return Err(anyhow::anyhow!("no sources to mix given"));
+  }
+  // the sources stop with the mixer, which stops with everything else
+  let sources_stop = Arc::new(AtomicBool::new(false));
+  let mut sources = Vec::with_capacity(options.mix.len());
+  for source in &options.mix {
+    if source.backend.eq_ignore_ascii_case("mix") {
+      return Err(anyhow::anyhow!("a mix can't contain another mix"));
+    }
+    let source_options = BackendOptions {
+      backend: Some(source.backend.clone()),
+      device: source.device.clone(),
+      mix: Vec::new(),
+      ..options.clone()
+    };
+    let (_, backend) = create(&source_options, config.clone(), Arc::clone(&sources_stop))?;
+    sources.push((source.clone(), backend));
+  }
+  Ok(Box::new(MixerBackend::new(
+    config,
+    sources,
+    options.mix_mode,
+    stop,
+    sources_stop,
+  )))
+}
+
+#[cfg(feature = "file")]
+fn create_file(
+  options: &BackendOptions,
+  config: AudioConfig,
+  stop: Arc<AtomicBool>,
+) -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+  use crate::audio::file::FileBackend;
+
+  let path = options
+    .file
+    .clone()
+    .ok_or_else(|| anyhow::anyhow!("no file given"))?;
+  FileBackend::probe(&path)?;
+  Ok(Box::new(FileBackend::new(
+    config,
+    path,
+    options.looping,
+    stop,
+  )))
+}
+
+#[cfg(feature = "pipe")]
+fn create_pipe(
+  options: &BackendOptions,
+  config: AudioConfig,
+  stop: Arc<AtomicBool>,
+) -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+  use crate::audio::pipe::PipeBackend;
+
+  let path = options
+    .pcm
+    .clone()
+    .ok_or_else(|| anyhow::anyhow!("no pcm input given"))?;
+  Ok(Box::new(PipeBackend::new(
+    config,
+    (path.as_os_str() != "-").then_some(path),
+    options.format,
+    options.rate,
+    options.channels,
+    stop,
+  )))
+}
+
+#[cfg(feature = "net")]
+fn create_net(
+  options: &BackendOptions,
+  _config: AudioConfig,
+  stop: Arc<AtomicBool>,
+) -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+  use crate::audio::net::NetBackend;
+
+  let addr = options
+    .listen
+    .as_deref()
+    .ok_or_else(|| anyhow::anyhow!("no listen address given"))?;
+  Ok(Box::new(NetBackend::new(
+    NetBackend::parse_addr(addr)?,
+    options.rtp,
+    options.format,
+    options.rate,
+    options.channels,
+    Duration::from_millis(options.jitter_buffer),
+    stop,
+  )))
+}
+
+#[cfg(feature = "generator")]
+fn create_generator(
+  options: &BackendOptions,
+  config: AudioConfig,
+  stop: Arc<AtomicBool>,
+) -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+  use crate::audio::generator::SignalGeneratorBackend;
+
+  let signal = options
+    .generate
+    .clone()
+    .ok_or_else(|| anyhow::anyhow!("no signal given"))?;
+  Ok(Box::new(SignalGeneratorBackend::new(
+    config,
+    signal,
+    options.amplitude,
+    options.rate,
+    options.channels,
+    stop,
+  )))
+}
+
+#[cfg(all(target_os = "windows", feature = "wasapi"))]
+fn create_wasapi(
+  options: &BackendOptions,
+  config: AudioConfig,
+  stop: Arc<AtomicBool>,
+) -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+  use crate::audio::wasapi::WasapiBackend;
+
+  check_device(options, WasapiBackend::devices)?;
+  Ok(Box::new(WasapiBackend::new(
+    config,
+    options.device.clone(),
+    stop,
+  )))
+}
+
+#[cfg(all(target_os = "linux", feature = "pulse"))]
+fn create_pulse(
+  options: &BackendOptions,
+  config: AudioConfig,
+  stop: Arc<AtomicBool>,
+) -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+  use crate::audio::pulse::PulseBackend;
+
+  PulseBackend::probe()?;
+  check_device(options, PulseBackend::devices)?;
+  Ok(Box::new(PulseBackend::new(
+    config,
+    options.device.clone(),
+    stop,
+  )))
+}
+
+#[cfg(feature = "jack")]
+fn create_jack(
+  options: &BackendOptions,
+  _config: AudioConfig,
+  stop: Arc<AtomicBool>,
+) -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+  use crate::audio::jack::JackBackend;
+
+  JackBackend::probe()?;
+  check_device(options, JackBackend::devices)?;
+  Ok(Box::new(JackBackend::new(
+    options.jack_connect.clone(),
+    options.device.clone(),
+    options.channels,
+    stop,
+  )))
+}
+
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+fn create_alsa(
+  options: &BackendOptions,
+  config: AudioConfig,
+  stop: Arc<AtomicBool>,
+) -> Result<Box<dyn AudioBackend>, anyhow::Error> {
+  use crate::audio::alsa::AlsaBackend;
+
+  let device = options.device.as_ref().unwrap_or(&options.alsa_device);
+  AlsaBackend::probe(device)?;
+  Ok(Box::new(AlsaBackend::new(
+    config,
+    device.clone(),
+    options.rate,
+    options.channels,
+    stop,
+  )))
+}
@@ -0,0 +1,199 @@
+use std::f64::consts::PI;
+
+use crate::audio::window::bessel_i0;
+
+// zero crossings of the sinc either side of its centre, at the lower rate
+const ZERO_CROSSINGS: usize = 16;
+// filter phases tabulated per input frame, blended linearly in between
+const PHASES: usize = 256;
+// kaiser window shape, roughly 90dB down in the stopband
+const KAISER_BETA: f64 = 8.6;
+// passband edge as a fraction of the lower nyquist, the rest is transition
+const ROLLOFF: f64 = 0.94;
+
+/// Polyphase windowed sinc resampler for interleaved audio, any ratio.
+///
+/// Input is carried over between calls so a stream can be fed in packets of
+/// any size without clicks at the joins.
+pub struct Resampler {
+  from: f32,
+  channels: usize,
+  // input frames per output frame
+  step: f64,
+  taps: usize,
+  // PHASES + 1 rows of taps, row p being the filter p / PHASES of a frame on
+  table: Vec<f32>,
+  // filter for the current output frame, blended from two rows
+  kernel: Vec<f32>,
+  // interleaved input that later output frames still reach
+  history: Vec<f32>,
+  // next output frame, in input frames from the start of history
+  time: f64,
+}
+
+impl Resampler {
+  pub fn new(from: f32, to: f32, channels: u16) -> Self {
+    let step = from as f64 / to as f64;
+    // cut off below whichever nyquist is lower, widening the filter to match
+    let cutoff = ROLLOFF * (1.0 / step).min(1.0);
+    let half = (ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+    let taps = 2 * half;
+
+    let mut table = Vec::with_capacity((PHASES + 1) * taps);
+    for phase in 0..=PHASES {
+      let frac = phase as f64 / PHASES as f64;
+      let row: Vec<f64> = (0..taps)
+        .map(|k| {
+          let x = k as f64 - (half - 1) as f64 - frac;
+          cutoff * sinc(cutoff * x) * kaiser(x / half as f64)
+        })
+        .collect();
+      // unity gain at dc whatever the phase
+      let sum: f64 = row.iter().sum();
+      table.extend(row.iter().map(|h| (h / sum) as f32));
+    }
+
+    let channels = channels.max(1) as usize;
+    Self {
+      from,
+      channels,
+      step,
+      taps,
+      table,
+      kernel: vec![0.0; taps],
+      // start as if silence came before, so the first output lines up with
+      // the first input
+      history: vec![0.0; (half - 1) * channels],
+      time: (half - 1) as f64,
+    }
+  }
+
+  /// Make room for calls of up to `frames` input frames, which then never
+  /// allocate, returning the most samples such a call can put out
+  pub fn reserve(&mut self, frames: usize) -> usize {
+    // no more than a filter's length is ever carried over
+    self.history.reserve((self.taps + frames) * self.channels);
+    ((frames as f64 / self.step).ceil() as usize + 1) * self.channels
+  }
+
+  /// Whether this resampler is set up for input of this rate and layout
+  pub fn matches(&self, from: f32, channels: u16) -> bool {
+    self.from == from && self.channels == channels.max(1) as usize
+  }
+
+  /// Resample `input` onto the end of `out`, returning where the first frame
+  /// written sits in input frames from the start of `input`. That is negative
+  /// when it comes from input passed in earlier.
+  pub fn process(&mut self, input: &[f32], out: &mut Vec<f32>) -> f64 {
+    let channels = self.channels;
+    let half = self.taps / 2;
+    let carried = self.history.len() / channels;
+    let first = self.time - carried as f64;
+
+    self
+      .history
+      .extend_from_slice(&input[..input.len() - input.len() % channels]);
+    let frames = self.history.len() / channels;
+
+    while (self.time as usize) + half < frames {
+      let base = self.time as usize;
+      let pos = self.time.fract() * PHASES as f64;
+      let phase = pos as usize;
+      let blend = (pos - phase as f64) as f32;
+      let row_a = &self.table[phase * self.taps..][..self.taps];
+      let row_b = &self.table[(phase + 1) * self.taps..][..self.taps];
+      for ((k, a), b) in self.kernel.iter_mut().zip(row_a).zip(row_b) {
+        *k = a + (b - a) * blend;
+      }
+
+      let start = (base + 1 - half) * channels;
+      let window = &self.history[start..start + self.taps * channels];
+      for ch in 0..channels {
+        let acc: f32 = window[ch..]
+          .iter()
+          .step_by(channels)
+          .zip(&self.kernel)
+          .map(|(s, k)| s * k)
+          .sum();
+        out.push(acc);
+      }
+      self.time += self.step;
+    }
+
+    // drop what no later output frame reaches back to
+    let used = (self.time as usize + 1).saturating_sub(half).min(frames);
+    self.history.drain(..used * channels);
+    self.time -= used as f64;
+    first
+  }
+}
+
+fn sinc(x: f64) -> f64 {
+  if x.abs() < 1e-9 {
+    1.0
+  } else {
+    (PI * x).sin() / (PI * x)
+  }
+}
+
+/// Kaiser window over -1..=1
+fn kaiser(x: f64) -> f64 {
+  if x.abs() > 1.0 {
+    return 0.0;
+  }
+  bessel_i0(KAISER_BETA * (1.0 - x * x).sqrt()) / bessel_i0(KAISER_BETA)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sine_keeps_its_pitch_and_level() {
+    let (from, to, freq) = (44_100.0, 48_000.0, 1000.0);
+    let input: Vec<f32> = (0..44_100)
+      .map(|n| 0.5 * (2.0 * PI * freq * n as f64 / from).sin() as f32)
+      .collect();
+
+    let mut resampler = Resampler::new(from as f32, to as f32, 1);
+    let mut output = Vec::new();
+    // uneven packets, as backends hand them over
+    for packet in input.chunks(441 + 7) {
+      resampler.process(packet, &mut output);
+    }
+    let expected = (input.len() as f64 * to / from) as usize;
+    assert!(
+      output.len().abs_diff(expected) < 64,
+      "{} frames out",
+      output.len()
+    );
+
+    // away from the filter's start up
+    let settled = &output[1000..output.len() - 1000];
+    let peak = settled.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+    assert!((peak - 0.5).abs() < 0.005, "peak {}", peak);
+
+    let rising = settled
+      .windows(2)
+      .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+      .count();
+    let measured = rising as f64 * to / settled.len() as f64;
+    assert!((measured - freq).abs() < 2.0, "{} Hz", measured);
+  }
+
+  #[test]
+  fn reserved_calls_fit() {
+    let mut resampler = Resampler::new(44_100.0, 48_000.0, 2);
+    let samples = resampler.reserve(256);
+    let history = resampler.history.capacity();
+    let mut output = Vec::with_capacity(samples);
+    let input = vec![0.25; 512];
+    for _ in 0..100 {
+      output.clear();
+      resampler.process(&input, &mut output);
+      assert!(output.len() <= samples);
+    }
+    assert_eq!(output.capacity(), samples);
+    assert_eq!(resampler.history.capacity(), history);
+  }
+}
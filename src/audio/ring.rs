@@ -0,0 +1,557 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering, fence};
+use std::time::{Duration, Instant};
+
+// packet marks kept, well over a second of the smallest jack periods
+const MARKS: usize = 1024;
+
+/// Sample rate and channel count of a stretch of samples in the ring
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RingFormat {
+  pub sample_rate: f32,
+  pub channels: u16,
+}
+
+impl RingFormat {
+  // zero channels stands for no format yet
+  fn encode(self) -> u64 {
+    ((self.sample_rate.to_bits() as u64) << 16) | self.channels as u64
+  }
+
+  fn decode(bits: u64) -> Option<Self> {
+    let channels = bits as u16;
+    (channels != 0).then(|| Self {
+      sample_rate: f32::from_bits((bits >> 16) as u32),
+      channels,
+    })
+  }
+}
+
+/// Metadata of a written packet, pinned to the ring position of its first
+/// sample
+#[derive(Clone, Copy, Debug)]
+pub struct RingMark {
+  pub timestamp: Instant,
+  pub position: u64,
+  pub sequence: u64,
+  pub discontinuity: bool,
+  pub glitch: bool,
+}
+
+/// Samples read from the ring, and what the marks say about them
+#[derive(Clone, Copy, Debug)]
+pub struct RingRead {
+  pub format: RingFormat,
+  /// Capture time and stream frame position of the first frame
+  pub timestamp: Instant,
+  pub position: u64,
+  /// Sequence numbers of the packets holding the first and last frames
+  pub first_sequence: u64,
+  pub last_sequence: u64,
+  /// Samples were lost before these, by the backend or by falling behind
+  pub discontinuity: bool,
+  /// A packet inside these was flagged as glitched
+  pub glitch: bool,
+}
+
+// mark flag bits
+const DISCONTINUITY: u64 = 1;
+const GLITCH: u64 = 2;
+
+/// One mark, guarded by its own seqlock so a lapped slot is never misread
+#[derive(Default)]
+struct MarkSlot {
+  // 2 * index + 1 while being written, 2 * index + 2 once done
+  seq: AtomicU64,
+  ring_pos: AtomicU64,
+  // nanoseconds either side of the ring's epoch
+  timestamp: AtomicU64,
+  position: AtomicU64,
+  sequence: AtomicU64,
+  flags: AtomicU64,
+}
+
+/// Lock-free single producer, single consumer ring of interleaved samples
+/// between a backend and the analysis.
+///
+/// The writer never waits on the reader. Once full it overwrites the oldest
+/// samples, and the reader notices when what it copied was overwritten under
+/// it. Positions count samples since the ring was created, so the reader can
+/// walk the history gap free at any hop. Whenever the writer's format changes
+/// a new stretch starts, and the reader skips straight to it. Each packet
+/// also leaves a mark with its timestamp, position and flags, from which the
+/// reader works out the same for whatever window it reads.
+pub struct SampleRing {
+  shared: Arc<Shared>,
+}
+
+struct Shared {
+  // f32 bits, atomics so a torn read is caught rather than undefined
+  samples: Box<[AtomicU32]>,
+  mask: u64,
+  // raised before samples are overwritten, then published once they land
+  claimed: AtomicU64,
+  written: AtomicU64,
+  // seqlock over the current stretch, odd while it is being replaced
+  stretch_seq: AtomicU64,
+  stretch_start: AtomicU64,
+  stretch_format: AtomicU64,
+  // timestamps are stored relative to this
+  epoch: Instant,
+  marks: Box<[MarkSlot]>,
+  mark_count: AtomicU64,
+}
+
+impl Shared {
+  fn capacity(&self) -> u64 {
+    self.samples.len() as u64
+  }
+
+  /// Where the current stretch starts and its format
+  fn stretch(&self) -> Option<(u64, RingFormat)> {
+    loop {
+      let seq = self.stretch_seq.load(Ordering::Acquire);
+      if seq % 2 == 1 {
+        std::hint::spin_loop();
+        continue;
+      }
+      let start = self.stretch_start.load(Ordering::Relaxed);
+      let format = self.stretch_format.load(Ordering::Relaxed);
+      fence(Ordering::Acquire);
+      if self.stretch_seq.load(Ordering::Relaxed) == seq {
+        return RingFormat::decode(format).map(|format| (start, format));
+      }
+    }
+  }
+
+  fn begin_stretch(&self, start: u64, format: RingFormat) {
+    let seq = self.stretch_seq.load(Ordering::Relaxed);
+    self.stretch_seq.store(seq + 1, Ordering::Relaxed);
+    fence(Ordering::Release);
+    self.stretch_start.store(start, Ordering::Relaxed);
+    self
+      .stretch_format
+      .store(format.encode(), Ordering::Relaxed);
+    self.stretch_seq.store(seq + 2, Ordering::Release);
+  }
+
+  fn push_mark(&self, ring_pos: u64, mark: RingMark) {
+    let index = self.mark_count.load(Ordering::Relaxed);
+    let slot = &self.marks[index as usize % MARKS];
+    let timestamp = match mark.timestamp.checked_duration_since(self.epoch) {
+      Some(after) => after.as_nanos() as i64,
+      None => -((self.epoch - mark.timestamp).as_nanos() as i64),
+    };
+    let flags =
+      if mark.discontinuity { DISCONTINUITY } else { 0 } | if mark.glitch { GLITCH } else { 0 };
+
+    slot.seq.store(2 * index + 1, Ordering::Relaxed);
+    fence(Ordering::Release);
+    slot.ring_pos.store(ring_pos, Ordering::Relaxed);
+    slot.timestamp.store(timestamp as u64, Ordering::Relaxed);
+    slot.position.store(mark.position, Ordering::Relaxed);
+    slot.sequence.store(mark.sequence, Ordering::Relaxed);
+    slot.flags.store(flags, Ordering::Relaxed);
+    slot.seq.store(2 * index + 2, Ordering::Release);
+    self.mark_count.store(index + 1, Ordering::Release);
+  }
+
+  /// Mark `index` and the ring position it is pinned to, none once lapped
+  fn mark(&self, index: u64) -> Option<(u64, RingMark)> {
+    let slot = &self.marks[index as usize % MARKS];
+    let seq = slot.seq.load(Ordering::Acquire);
+    if seq != 2 * index + 2 {
+      return None;
+    }
+    let ring_pos = slot.ring_pos.load(Ordering::Relaxed);
+    let timestamp = slot.timestamp.load(Ordering::Relaxed) as i64;
+    let position = slot.position.load(Ordering::Relaxed);
+    let sequence = slot.sequence.load(Ordering::Relaxed);
+    let flags = slot.flags.load(Ordering::Relaxed);
+    fence(Ordering::Acquire);
+    if slot.seq.load(Ordering::Relaxed) != seq {
+      return None;
+    }
+
+    let offset = Duration::from_nanos(timestamp.unsigned_abs());
+    let timestamp = if timestamp >= 0 {
+      self.epoch + offset
+    } else {
+      self.epoch - offset
+    };
+    let mark = RingMark {
+      timestamp,
+      position,
+      sequence,
+      discontinuity: flags & DISCONTINUITY != 0,
+      glitch: flags & GLITCH != 0,
+    };
+    Some((ring_pos, mark))
+  }
+
+  /// Oldest position that has not been overwritten
+  fn oldest(&self) -> u64 {
+    self
+      .claimed
+      .load(Ordering::Relaxed)
+      .saturating_sub(self.capacity())
+  }
+}
+
+impl SampleRing {
+  /// Ring holding at least `capacity` samples, rounded up to a power of two
+  pub fn new(capacity: usize) -> Self {
+    let capacity = capacity.max(1).next_power_of_two();
+    let shared = Shared {
+      samples: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+      mask: capacity as u64 - 1,
+      claimed: AtomicU64::new(0),
+      written: AtomicU64::new(0),
+      stretch_seq: AtomicU64::new(0),
+      stretch_start: AtomicU64::new(0),
+      stretch_format: AtomicU64::new(0),
+      epoch: Instant::now(),
+      marks: (0..MARKS).map(|_| MarkSlot::default()).collect(),
+      mark_count: AtomicU64::new(0),
+    };
+    Self {
+      shared: Arc::new(shared),
+    }
+  }
+
+  /// Write side, only one may be in use at a time
+  pub fn writer(&self) -> RingWriter {
+    RingWriter {
+      shared: Arc::clone(&self.shared),
+    }
+  }
+
+  /// Read side, starting from whatever is written next
+  pub fn reader(&self) -> RingReader {
+    RingReader {
+      shared: Arc::clone(&self.shared),
+      cursor: self.shared.written.load(Ordering::Acquire),
+      read_end: self.shared.written.load(Ordering::Acquire),
+    }
+  }
+}
+
+pub struct RingWriter {
+  shared: Arc<Shared>,
+}
+
+impl RingWriter {
+  /// Format of the most recent samples, none before anything was written
+  pub fn format(&self) -> Option<RingFormat> {
+    self.shared.stretch().map(|(_, format)| format)
+  }
+
+  /// Append interleaved samples, dropping any trailing partial frame, with
+  /// `mark` describing the first of them
+  pub fn write(&mut self, samples: &[f32], format: RingFormat, mark: RingMark) {
+    let shared = &*self.shared;
+    let channels = format.channels.max(1) as usize;
+    let samples = &samples[..samples.len() - samples.len() % channels];
+    if samples.is_empty() {
+      return;
+    }
+
+    let start = shared.written.load(Ordering::Relaxed);
+    if self.format() != Some(format) {
+      shared.begin_stretch(start, format);
+    }
+
+    shared.push_mark(start, mark);
+
+    let end = start + samples.len() as u64;
+    shared.claimed.store(end, Ordering::Relaxed);
+    fence(Ordering::Release);
+    for (pos, s) in (start..end).zip(samples) {
+      shared.samples[(pos & shared.mask) as usize].store(s.to_bits(), Ordering::Relaxed);
+    }
+    shared.written.store(end, Ordering::Release);
+  }
+}
+
+pub struct RingReader {
+  shared: Arc<Shared>,
+  // next position to read from
+  cursor: u64,
+  // where the last read ended, anything skipped past it is a discontinuity
+  read_end: u64,
+}
+
+impl RingReader {
+  /// Total samples written since the ring was created
+  pub fn written(&self) -> u64 {
+    self.shared.written.load(Ordering::Acquire)
+  }
+
+  /// Skip everything written so far, reading on from whatever comes next
+  #[cfg_attr(not(feature = "file"), allow(dead_code))]
+  pub fn skip_to_end(&mut self) {
+    let written = self.written();
+    self.cursor = written;
+    self.read_end = written;
+  }
+
+  /// Copy the next `frames` frames into `out` and move on by `hop` frames,
+  /// none until that many have been written
+  pub fn next(&mut self, frames: usize, hop: usize, out: &mut Vec<f32>) -> Option<RingRead> {
+    let shared = &*self.shared;
+
+    loop {
+      let written = shared.written.load(Ordering::Acquire);
+      let (start, format) = shared.stretch()?;
+      if start > written {
+        // a new stretch began after we looked, nothing of it is written yet
+        return None;
+      }
+
+      let channels = format.channels as u64;
+      let len = frames as u64 * channels;
+      if len > shared.capacity() {
+        return None;
+      }
+
+      // leftovers of an older format are dropped, as is anything overwritten
+      if self.cursor < start {
+        self.cursor = start;
+      }
+      let oldest = shared.oldest();
+      if self.cursor < oldest {
+        // fell too far behind, resume from the newest window
+        self.cursor = written.saturating_sub(len).max(oldest);
+      }
+      // keep to the stretch's frame boundaries
+      self.cursor += (channels - (self.cursor - start) % channels) % channels;
+      if self.cursor + len > written {
+        return None;
+      }
+
+      out.clear();
+      out.extend((self.cursor..self.cursor + len).map(|pos| {
+        f32::from_bits(shared.samples[(pos & shared.mask) as usize].load(Ordering::Relaxed))
+      }));
+      fence(Ordering::Acquire);
+      if self.cursor < shared.oldest() {
+        // lapped while copying, go round again
+        continue;
+      }
+
+      let to = self.cursor + len;
+      let read = self.describe(self.cursor, to, format);
+      self.cursor += hop as u64 * channels;
+      self.read_end = self.read_end.max(to);
+      return Some(read);
+    }
+  }
+
+  /// Work out timing and flags for the samples in `from..to` from the marks
+  fn describe(&self, from: u64, to: u64, format: RingFormat) -> RingRead {
+    let shared = &*self.shared;
+    let mut read = RingRead {
+      format,
+      timestamp: Instant::now(),
+      position: 0,
+      first_sequence: 0,
+      last_sequence: 0,
+      discontinuity: from > self.read_end,
+      glitch: false,
+    };
+
+    // newest first, until the mark holding the first frame
+    let count = shared.mark_count.load(Ordering::Acquire);
+    let mut found_last = false;
+    let mut first = None;
+    for index in (count.saturating_sub(MARKS as u64)..count).rev() {
+      let Some((ring_pos, mark)) = shared.mark(index) else {
+        break;
+      };
+      if ring_pos >= to {
+        continue;
+      }
+      if !found_last {
+        read.last_sequence = mark.sequence;
+        found_last = true;
+      }
+      // overlapping reads only report a packet's flags the first time round
+      if ring_pos >= from.max(self.read_end) {
+        read.discontinuity |= mark.discontinuity;
+        read.glitch |= mark.glitch;
+      }
+      first = Some((ring_pos, mark));
+      if ring_pos <= from {
+        break;
+      }
+    }
+
+    if let Some((ring_pos, mark)) = first {
+      // offset from the mark's first frame, which can be either side of ours
+      let frames = (from as i64 - ring_pos as i64) / format.channels as i64;
+      let offset =
+        Duration::from_secs_f64(frames.unsigned_abs() as f64 / format.sample_rate as f64);
+      read.timestamp = if frames >= 0 {
+        mark.timestamp + offset
+      } else {
+        mark.timestamp - offset
+      };
+      read.position = mark.position.saturating_add_signed(frames);
+      read.first_sequence = mark.sequence;
+      if !found_last {
+        read.last_sequence = mark.sequence;
+      }
+    }
+    read
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::thread;
+
+  const MONO: RingFormat = RingFormat {
+    sample_rate: 48_000.0,
+    channels: 1,
+  };
+
+  fn mark(position: u64) -> RingMark {
+    RingMark {
+      timestamp: Instant::now(),
+      position,
+      sequence: position,
+      discontinuity: false,
+      glitch: false,
+    }
+  }
+
+  /// Write `range` as samples of their own value
+  fn write(writer: &mut RingWriter, range: std::ops::Range<u32>) {
+    let samples: Vec<f32> = range.clone().map(|n| n as f32).collect();
+    writer.write(&samples, MONO, mark(range.start as u64));
+  }
+
+  #[test]
+  fn reads_on_across_the_wrap() {
+    let ring = SampleRing::new(16);
+    let mut writer = ring.writer();
+    let mut reader = ring.reader();
+    let mut out = Vec::new();
+
+    write(&mut writer, 0..12);
+    let read = reader.next(8, 8, &mut out).unwrap();
+    assert_eq!(out, (0..8).map(|n| n as f32).collect::<Vec<_>>());
+    assert!(!read.discontinuity);
+    assert!(reader.next(8, 8, &mut out).is_none());
+
+    // lands on slots 12..16 then 0..8
+    write(&mut writer, 12..24);
+    let read = reader.next(8, 8, &mut out).unwrap();
+    assert_eq!(out, (8..16).map(|n| n as f32).collect::<Vec<_>>());
+    assert_eq!(read.position, 8);
+    assert!(!read.discontinuity);
+    reader.next(8, 8, &mut out).unwrap();
+    assert_eq!(out, (16..24).map(|n| n as f32).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn overrun_resumes_from_the_newest_window() {
+    let ring = SampleRing::new(16);
+    let mut writer = ring.writer();
+    let mut reader = ring.reader();
+    let mut out = Vec::new();
+
+    write(&mut writer, 0..8);
+    reader.next(4, 4, &mut out).unwrap();
+    // laps the reader, 4..24 is gone
+    write(&mut writer, 8..40);
+    let read = reader.next(4, 4, &mut out).unwrap();
+    assert_eq!(out, [36.0, 37.0, 38.0, 39.0]);
+    assert_eq!(read.position, 36);
+    assert!(read.discontinuity);
+
+    write(&mut writer, 40..44);
+    let read = reader.next(4, 4, &mut out).unwrap();
+    assert_eq!(out, [40.0, 41.0, 42.0, 43.0]);
+    assert!(!read.discontinuity);
+  }
+
+  #[test]
+  fn skip_to_end_waits_for_new_samples() {
+    let ring = SampleRing::new(64);
+    let mut writer = ring.writer();
+    let mut reader = ring.reader();
+    let mut out = Vec::new();
+
+    write(&mut writer, 0..32);
+    reader.skip_to_end();
+    assert!(reader.next(4, 4, &mut out).is_none());
+
+    write(&mut writer, 32..36);
+    let read = reader.next(4, 4, &mut out).unwrap();
+    assert_eq!(out, [32.0, 33.0, 34.0, 35.0]);
+    assert_eq!(read.position, 32);
+    assert!(!read.discontinuity);
+  }
+
+  #[test]
+  fn concurrent_reads_are_never_torn_or_reordered() {
+    const FRAMES: u32 = 1 << 20;
+    const PACKET: u32 = 96;
+    const STEREO: RingFormat = RingFormat {
+      sample_rate: 48_000.0,
+      channels: 2,
+    };
+
+    // small enough that the reader is lapped now and then
+    let ring = SampleRing::new(1024);
+    let mut writer = ring.writer();
+    let mut reader = ring.reader();
+
+    let producer = thread::spawn(move || {
+      let mut samples = Vec::new();
+      for start in (0..FRAMES).step_by(PACKET as usize) {
+        samples.clear();
+        // both channels carry the frame number, opposite signs
+        samples.extend((start..start + PACKET).flat_map(|n| [n as f32, -(n as f32)]));
+        writer.write(&samples, STEREO, mark(start as u64));
+      }
+    });
+
+    let mut out = Vec::new();
+    let mut next_frame = 0;
+    let mut reads = 0;
+    while next_frame < FRAMES {
+      let Some(read) = reader.next(64, 64, &mut out) else {
+        if producer.is_finished() && reader.written() == 2 * FRAMES as u64 {
+          break;
+        }
+        thread::yield_now();
+        continue;
+      };
+      let first = out[0] as u32;
+      assert_eq!(read.format, STEREO);
+      assert_eq!(read.position, first as u64);
+      if read.discontinuity {
+        assert!(
+          first >= next_frame,
+          "went back from {} to {}",
+          next_frame,
+          first
+        );
+      } else {
+        assert_eq!(first, next_frame);
+      }
+      for (i, frame) in out.chunks_exact(2).enumerate() {
+        let n = (first + i as u32) as f32;
+        assert_eq!(frame, [n, -n], "torn frame at {}", first + i as u32);
+      }
+      next_frame = first + 64;
+      reads += 1;
+    }
+    producer.join().unwrap();
+    assert!(reads > 0);
+  }
+}
@@ -0,0 +1,254 @@
+use std::str::FromStr;
+
+// ratio between iec 61260 octave bands, base ten
+const OCTAVE_RATIO: f64 = 1.9952623149688795;
+// nominal band centres are rounded, so let the ends of the range in by this
+const OCTAVE_TOLERANCE: f64 = 0.01;
+
+/// How the bars are spread over the frequency range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrequencyScale {
+  Linear,
+  Logarithmic,
+  Mel,
+  Bark,
+  /// Equivalent rectangular bandwidths of the ear
+  Erb,
+  /// Iec 61260 fractional octave bands, 1/N of an octave each, as many as
+  /// have their centre in range
+  Octave(u32),
+}
+
+impl FromStr for FrequencyScale {
+  type Err = anyhow::Error;
+
+  /// Parses `linear`, `log`, `mel`, `bark`, `erb` and `octave[:N]`, N being 1,
+  /// 3 (the default), 6 or 12
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (kind, param) = s.split_once(':').unwrap_or((s, ""));
+    let scale = match kind.to_ascii_lowercase().as_str() {
+      "linear" => FrequencyScale::Linear,
+      "log" | "logarithmic" => FrequencyScale::Logarithmic,
+      "mel" => FrequencyScale::Mel,
+      "bark" => FrequencyScale::Bark,
+      "erb" => FrequencyScale::Erb,
+      "octave" => {
+        let fraction = match param.trim() {
+          "" => 3,
+          n => n
+            .parse::<u32>()
+            .ok()
+            .filter(|n| matches!(n, 1 | 3 | 6 | 12))
+            .ok_or_else(|| anyhow::anyhow!("octave fraction '{}' must be 1, 3, 6 or 12", n))?,
+        };
+        return Ok(FrequencyScale::Octave(fraction));
+      }
+      _ => {
+        return Err(anyhow::anyhow!(
+          "unknown scale '{}', expected linear, log, mel, bark, erb or octave",
+          s
+        ));
+      }
+    };
+    if !param.is_empty() {
+      return Err(anyhow::anyhow!("scale '{}' takes no parameter", kind));
+    }
+    Ok(scale)
+  }
+}
+
+impl FrequencyScale {
+  /// Edges of the bands between `min` and `max` in Hz, each band running from
+  /// one edge up to the next. `count` bands are spaced evenly on the scale,
+  /// except for octave bands, which are fixed and decide their own number
+  pub fn edges(&self, count: usize, min: f32, max: f32) -> Vec<f32> {
+    let (min, max) = (min as f64, max as f64);
+    if let FrequencyScale::Octave(fraction) = *self {
+      return octave_edges(fraction, min, max)
+        .into_iter()
+        .map(|f| f as f32)
+        .collect();
+    }
+
+    let count = count.max(1);
+    let (low, high) = (self.warp(min), self.warp(max));
+    (0..=count)
+      .map(|i| {
+        let x = low + (high - low) * i as f64 / count as f64;
+        self.unwarp(x) as f32
+      })
+      .collect()
+  }
+
+  /// Centre of the band from `low` to `high`, halfway between on the scale
+  pub fn centre(&self, low: f32, high: f32) -> f32 {
+    let (low, high) = (low as f64, high as f64);
+    self.unwarp((self.warp(low) + self.warp(high)) / 2.0) as f32
+  }
+
+  /// Position of `freq` on the scale
+  fn warp(&self, freq: f64) -> f64 {
+    match *self {
+      FrequencyScale::Linear => freq,
+      FrequencyScale::Logarithmic | FrequencyScale::Octave(_) => freq.max(f64::MIN_POSITIVE).ln(),
+      FrequencyScale::Mel => 2595.0 * (1.0 + freq / 700.0).log10(),
+      // traunmüller's approximation
+      FrequencyScale::Bark => 26.81 * freq / (1960.0 + freq) - 0.53,
+      // glasberg and moore's erb rate
+      FrequencyScale::Erb => 21.4 * (1.0 + 0.00437 * freq).log10(),
+    }
+  }
+
+  /// Frequency at `x` on the scale
+  fn unwarp(&self, x: f64) -> f64 {
+    match *self {
+      FrequencyScale::Linear => x,
+      FrequencyScale::Logarithmic | FrequencyScale::Octave(_) => x.exp(),
+      FrequencyScale::Mel => 700.0 * (10f64.powf(x / 2595.0) - 1.0),
+      FrequencyScale::Bark => 1960.0 * (x + 0.53) / (26.28 - x),
+      FrequencyScale::Erb => (10f64.powf(x / 21.4) - 1.0) / 0.00437,
+    }
+  }
+}
+
+/// Edges of the 1/`fraction` octave bands centred between `min` and `max`,
+/// at least the one around the middle of the range should none fit
+fn octave_edges(fraction: u32, min: f64, max: f64) -> Vec<f64> {
+  let b = fraction as f64;
+  // bands are counted from 1kHz, even fractions sitting either side of it
+  let offset = if fraction.is_multiple_of(2) { 0.5 } else { 0.0 };
+  let centre = |x: i32| 1000.0 * OCTAVE_RATIO.powf((x as f64 + offset) / b);
+  let index = |freq: f64| (b * (freq / 1000.0).log(OCTAVE_RATIO) - offset).round() as i32;
+
+  let first = index(min.max(f64::MIN_POSITIVE));
+  let last = index(max.max(f64::MIN_POSITIVE));
+  let bands: Vec<i32> = (first..=last)
+    .filter(|&x| {
+      let fm = centre(x);
+      fm >= min * (1.0 - OCTAVE_TOLERANCE) && fm <= max * (1.0 + OCTAVE_TOLERANCE)
+    })
+    .collect();
+  let (first, last) = match (bands.first(), bands.last()) {
+    (Some(&first), Some(&last)) => (first, last),
+    _ => {
+      let middle = index((min * max).sqrt().max(f64::MIN_POSITIVE));
+      (middle, middle)
+    }
+  };
+
+  let half = OCTAVE_RATIO.powf(0.5 / b);
+  (first..=last)
+    .map(|x| centre(x) / half)
+    .chain(std::iter::once(centre(last) * half))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SCALES: [FrequencyScale; 6] = [
+    FrequencyScale::Linear,
+    FrequencyScale::Logarithmic,
+    FrequencyScale::Mel,
+    FrequencyScale::Bark,
+    FrequencyScale::Erb,
+    FrequencyScale::Octave(3),
+  ];
+
+  #[test]
+  fn edges_climb_from_min_to_max() {
+    for scale in SCALES {
+      let edges = scale.edges(64, 20.0, 20_000.0);
+      assert!(
+        edges.windows(2).all(|e| e[0] < e[1]),
+        "{:?} edges don't climb",
+        scale
+      );
+      let (first, last) = (edges[0], edges[edges.len() - 1]);
+      match scale {
+        // whole bands, so they reach just past either end
+        FrequencyScale::Octave(fraction) => {
+          assert!(first <= 20.0 && last >= 20_000.0, "{:?}", scale);
+          // each a fixed fraction of an octave on from the last
+          let ratio = OCTAVE_RATIO.powf(1.0 / fraction as f64) as f32;
+          for e in edges.windows(2) {
+            assert!(
+              (e[1] / e[0] - ratio).abs() < 1e-4,
+              "{:?} at {}",
+              scale,
+              e[0]
+            );
+          }
+        }
+        _ => {
+          assert_eq!(edges.len(), 65, "{:?}", scale);
+          assert!(
+            (first - 20.0).abs() < 1e-3,
+            "{:?} starts at {}",
+            scale,
+            first
+          );
+          assert!(
+            (last - 20_000.0).abs() < 1.0,
+            "{:?} ends at {}",
+            scale,
+            last
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn warping_comes_back_to_the_same_frequency() {
+    for scale in SCALES {
+      for freq in [20.0, 100.0, 440.0, 1000.0, 6000.0, 20_000.0] {
+        let back = scale.unwarp(scale.warp(freq));
+        assert!(
+          (back - freq).abs() < freq * 1e-9,
+          "{:?} took {} back to {}",
+          scale,
+          freq,
+          back
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn octave_centres_land_on_the_nominal_values() {
+    let nominal: [(u32, &[f32]); 2] = [
+      (
+        1,
+        &[
+          31.5, 63.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16_000.0,
+        ],
+      ),
+      (
+        3,
+        &[
+          20.0, 25.0, 31.5, 40.0, 50.0, 63.0, 80.0, 100.0, 125.0, 160.0, 200.0, 250.0, 315.0,
+          400.0, 500.0, 630.0, 800.0, 1000.0, 1250.0, 1600.0, 2000.0, 2500.0, 3150.0, 4000.0,
+          5000.0, 6300.0, 8000.0, 10_000.0, 12_500.0, 16_000.0, 20_000.0,
+        ],
+      ),
+    ];
+    for (fraction, centres) in nominal {
+      let scale = FrequencyScale::Octave(fraction);
+      let edges = scale.edges(0, 20.0, 20_000.0);
+      assert_eq!(edges.len(), centres.len() + 1, "1/{} octave", fraction);
+      for (e, &expected) in edges.windows(2).zip(centres) {
+        let centre = scale.centre(e[0], e[1]);
+        // nominal values are rounded by up to a percent or so
+        assert!(
+          (centre / expected - 1.0).abs() < 0.015,
+          "1/{} octave centre {} against {}",
+          fraction,
+          centre,
+          expected
+        );
+      }
+    }
+  }
+}
@@ -0,0 +1,386 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use tracing::{error, info, warn};
+
+use crate::audio::AudioConfig;
+use crate::audio::backend::{AudioBackend, AudioPacket, AudioSender};
+use crate::audio::registry::{BackendEntry, BackendOptions};
+use crate::audio::ring::{RingReader, SampleRing};
+
+// first retry delay, doubled on every failed attempt up to the max
+const MIN_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+// how often silence is written while waiting to retry
+const SILENCE_INTERVAL: Duration = Duration::from_millis(50);
+// how long without new samples before the receiver treats capture as silent
+const STALL_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Where the supervised backend is at, for the visualiser to show
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ConnectionState {
+  #[default]
+  Connected,
+  /// Waiting to restart after a failure, attempts count from 1
+  Reconnecting { attempt: u32 },
+  /// The source ran out or capture was stopped
+  Finished,
+}
+
+/// Read side of the supervised backend, which follows it across restarts
+pub struct AudioReceiver {
+  ring: RingReader,
+  state: Arc<Mutex<ConnectionState>>,
+  // reused for every window handed out
+  packet: AudioPacket,
+  // ring position when samples last turned up, and when that was
+  last_written: u64,
+  last_progress: Instant,
+  // sequence number of the newest packet read, and how many went missing
+  last_sequence: Option<u64>,
+  dropped: u64,
+}
+
+impl AudioReceiver {
+  pub fn state(&self) -> ConnectionState {
+    *lock(&self.state)
+  }
+
+  /// Packets lost so far, overwritten before they were read
+  pub fn dropped(&self) -> u64 {
+    self.dropped
+  }
+
+  /// Run `f` on every window of `frames` frames captured since the last call,
+  /// hopping a whole window each time so every sample is seen once. Once
+  /// capture stalls, `f` gets a silent packet instead so the visuals settle
+  pub fn read(&mut self, frames: usize, mut f: impl FnMut(&AudioPacket)) {
+    let written = self.ring.written();
+    if written != self.last_written {
+      self.last_written = written;
+      self.last_progress = Instant::now();
+    }
+
+    let mut windows = 0;
+    while let Some(read) = self.ring.next(frames, frames, &mut self.packet.samples) {
+      // a run's sequence numbers only ever count up, a jump means we fell
+      // behind and whole packets were overwritten unread
+      if let Some(last) = self.last_sequence
+        && read.first_sequence > last + 1
+      {
+        self.dropped += read.first_sequence - last - 1;
+        warn!(
+          "analysis fell behind, {} audio packets dropped so far...",
+          self.dropped
+        );
+      }
+      self.last_sequence = Some(read.last_sequence);
+
+      self.packet.sample_rate = read.format.sample_rate;
+      self.packet.channels = read.format.channels;
+      self.packet.timestamp = read.timestamp;
+      self.packet.position = read.position;
+      self.packet.sequence = read.first_sequence;
+      self.packet.discontinuity = read.discontinuity;
+      self.packet.glitch = read.glitch;
+      self.packet.is_silent = self.packet.samples.iter().all(|&s| s == 0.0);
+      f(&self.packet);
+      windows += 1;
+    }
+
+    // nothing whole yet is normal between packets, only a stall is silence
+    if windows == 0 && self.last_progress.elapsed() >= STALL_TIMEOUT {
+      self.packet.samples.clear();
+      self.packet.is_silent = true;
+      self.packet.timestamp = Instant::now();
+      self.packet.discontinuity = false;
+      self.packet.glitch = false;
+      f(&self.packet);
+    }
+  }
+}
+
+/// Keeps a backend running, recreating it with exponential backoff whenever
+/// it fails (device unplugged, server restarted, ...).
+///
+/// Every attempt writes into the same sample ring, and silence fills the gaps
+/// in between so the visualiser decays rather than freezing.
+pub struct Supervisor {
+  entry: &'static BackendEntry,
+  options: BackendOptions,
+  config: AudioConfig,
+  stop: Arc<AtomicBool>,
+  ring: SampleRing,
+  // what the backends capture before it's resampled, once anything reads it
+  capture: Option<SampleRing>,
+  state: Arc<Mutex<ConnectionState>>,
+}
+
+impl Supervisor {
+  pub fn new(
+    entry: &'static BackendEntry,
+    options: BackendOptions,
+    config: AudioConfig,
+    stop: Arc<AtomicBool>,
+  ) -> (Self, AudioReceiver) {
+    let ring = SampleRing::new(config.history_size);
+    let state = Arc::new(Mutex::new(ConnectionState::Connected));
+    let receiver = AudioReceiver {
+      ring: ring.reader(),
+      state: Arc::clone(&state),
+      packet: AudioPacket::default(),
+      last_written: 0,
+      last_progress: Instant::now(),
+      last_sequence: None,
+      dropped: 0,
+    };
+    let supervisor = Self {
+      entry,
+      options,
+      config,
+      stop,
+      ring,
+      capture: None,
+      state,
+    };
+    (supervisor, receiver)
+  }
+
+  /// Another reader of everything analysed, independent of the receiver's
+  #[cfg(test)]
+  pub fn reader(&self) -> RingReader {
+    self.ring.reader()
+  }
+
+  /// Reader of everything the backends capture, at their own rate rather
+  /// than resampled for the analysis
+  #[cfg_attr(not(feature = "file"), allow(dead_code))]
+  pub fn capture_reader(&mut self) -> RingReader {
+    let size = self.config.history_size;
+    self
+      .capture
+      .get_or_insert_with(|| SampleRing::new(size))
+      .reader()
+  }
+
+  /// Run `backend`, and its replacements, until one ends cleanly or we are
+  /// stopped
+  pub async fn run(self, mut backend: Box<dyn AudioBackend>) {
+    let name = self.entry.name;
+    let mut backoff = Backoff::new();
+
+    loop {
+      self.set_state(ConnectionState::Connected);
+      let started = Instant::now();
+      let tx = AudioSender::new(self.ring.writer(), self.config.analysis_rate)
+        .with_capture(self.capture.as_ref().map(SampleRing::writer));
+      let result = backend.run(tx).await;
+      match result {
+        Ok(()) => break,
+        Err(e) => error!("{} audio backend error - {}", name, e),
+      }
+
+      // a run that held up for a while earns a fresh backoff
+      if started.elapsed() >= MAX_BACKOFF {
+        backoff = Backoff::new();
+      }
+
+      backend = loop {
+        let (attempt, delay) = backoff.next();
+        self.set_state(ConnectionState::Reconnecting { attempt });
+        warn!(
+          "restarting {} audio backend in {:?} (attempt {})...",
+          name, delay, attempt
+        );
+        if !self.wait_silent(delay).await {
+          self.set_state(ConnectionState::Finished);
+          return;
+        }
+
+        match self
+          .entry
+          .create(&self.options, self.config.clone(), Arc::clone(&self.stop))
+        {
+          Ok(backend) => {
+            info!("{} audio backend reconnected...", name);
+            break backend;
+          }
+          Err(e) => warn!("{} audio backend still unavailable - {}", name, e),
+        }
+      };
+    }
+
+    self.set_state(ConnectionState::Finished);
+  }
+
+  fn set_state(&self, state: ConnectionState) {
+    *lock(&self.state) = state;
+  }
+
+  /// Write silence at the last format's pace for `duration`, false if we were
+  /// stopped meanwhile
+  async fn wait_silent(&self, duration: Duration) -> bool {
+    // the backend is gone, so we are the ring's only writer
+    let writer = self.ring.writer();
+    let mut silence = writer.format().map(|format| {
+      let frames = (format.sample_rate * SILENCE_INTERVAL.as_secs_f32()) as usize;
+      AudioPacket {
+        samples: vec![0.0; frames * format.channels as usize],
+        is_silent: true,
+        ..AudioPacket::new(format.sample_rate, format.channels, 0)
+      }
+    });
+    let mut tx = AudioSender::new(writer, self.config.analysis_rate);
+
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+      if self.stop.load(Ordering::Relaxed) {
+        return false;
+      }
+      if let Some(packet) = &mut silence {
+        packet.timestamp = Instant::now();
+        tx.write(packet);
+      }
+      tokio::time::sleep(SILENCE_INTERVAL.min(deadline.saturating_duration_since(Instant::now())))
+        .await;
+    }
+    !self.stop.load(Ordering::Relaxed)
+  }
+}
+
+/// Delays between restarts, doubling from the min to the max
+struct Backoff {
+  attempt: u32,
+  delay: Duration,
+}
+
+impl Backoff {
+  fn new() -> Self {
+    Self {
+      attempt: 0,
+      delay: MIN_BACKOFF,
+    }
+  }
+
+  /// The next attempt, counting from 1, and how long to wait before it
+  fn next(&mut self) -> (u32, Duration) {
+    let delay = self.delay;
+    self.attempt += 1;
+    self.delay = (delay * 2).min(MAX_BACKOFF);
+    (self.attempt, delay)
+  }
+}
+
+fn lock(state: &Mutex<ConnectionState>) -> MutexGuard<'_, ConnectionState> {
+  // a plain value can't be left half written, carry on after a panic
+  state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::AtomicU32;
+
+  use async_trait::async_trait;
+  use clap::Parser;
+
+  use crate::audio::test_config;
+
+  #[derive(Parser)]
+  struct Cli {
+    #[command(flatten)]
+    options: BackendOptions,
+  }
+
+  // runs of `Flaky` so far, and when each started
+  static RUNS: AtomicU32 = AtomicU32::new(0);
+  static STARTS: Mutex<Vec<Instant>> = Mutex::new(Vec::new());
+  const FAILURES: u32 = 2;
+
+  /// Writes a packet of full scale, then fails the first `FAILURES` times
+  /// it's run
+  struct Flaky;
+
+  #[async_trait]
+  impl AudioBackend for Flaky {
+    async fn run(self: Box<Self>, mut tx: AudioSender) -> Result<(), anyhow::Error> {
+      STARTS.lock().unwrap().push(Instant::now());
+      let mut packet = AudioPacket::new(48_000.0, 1, 480);
+      packet.samples.resize(480, 1.0);
+      packet.timestamp = Instant::now();
+      tx.write(&mut packet);
+      if RUNS.fetch_add(1, Ordering::Relaxed) < FAILURES {
+        Err(anyhow::anyhow!("unplugged"))
+      } else {
+        Ok(())
+      }
+    }
+  }
+
+  static FLAKY: BackendEntry = BackendEntry::new("flaky", |_, _, _| Ok(Box::new(Flaky)));
+
+  #[test]
+  fn backoff_doubles_up_to_the_max() {
+    let mut backoff = Backoff::new();
+    let delays: Vec<_> = (0..8).map(|_| backoff.next()).collect();
+    let ms = |ms| Duration::from_millis(ms);
+    assert_eq!(
+      delays,
+      [
+        (1, ms(250)),
+        (2, ms(500)),
+        (3, ms(1000)),
+        (4, ms(2000)),
+        (5, ms(4000)),
+        (6, ms(8000)),
+        (7, MAX_BACKOFF),
+        (8, MAX_BACKOFF),
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn restarts_a_failing_backend_with_silence_in_between() {
+    let options = Cli::parse_from(["field"]).options;
+    let stop = Arc::new(AtomicBool::new(false));
+    let (supervisor, receiver) = Supervisor::new(&FLAKY, options, test_config(), stop);
+    let mut reader = supervisor.reader();
+    let run = tokio::spawn(supervisor.run(Box::new(Flaky)));
+
+    let (mut attempts, mut loud, mut silent) = (Vec::new(), 0, 0);
+    let mut chunk = Vec::new();
+    loop {
+      let state = receiver.state();
+      if let ConnectionState::Reconnecting { attempt } = state
+        && attempts.last() != Some(&attempt)
+      {
+        attempts.push(attempt);
+      }
+      while reader.next(480, 480, &mut chunk).is_some() {
+        loud += chunk.iter().filter(|&&s| s == 1.0).count();
+        silent += chunk.iter().filter(|&&s| s == 0.0).count();
+      }
+      if state == ConnectionState::Finished {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    run.await.unwrap();
+    // the last run's packet may have landed after the last look
+    while reader.next(480, 480, &mut chunk).is_some() {
+      loud += chunk.iter().filter(|&&s| s == 1.0).count();
+    }
+
+    assert_eq!(attempts, [1, 2]);
+    assert_eq!(RUNS.load(Ordering::Relaxed), FAILURES + 1);
+    assert_eq!(loud, 480 * (FAILURES as usize + 1));
+    // most of the 750ms spent waiting is filled
+    assert!(silent > 48 * 500, "{} frames of silence", silent);
+
+    let starts = STARTS.lock().unwrap();
+    let gaps: Vec<_> = starts.windows(2).map(|w| w[1] - w[0]).collect();
+    assert!(gaps[0] >= MIN_BACKOFF, "{:?}", gaps);
+    assert!(gaps[1] >= 2 * MIN_BACKOFF, "{:?}", gaps);
+  }
+}
@@ -3,50 +3,76 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
-use triple_buffer::Input;
+use async_trait::async_trait;
 
 use tokio::task;
 
 use tracing::info;
 
+use windows::Win32::Devices::FunctionDiscovery::PKEY_Device_FriendlyName;
 use windows::Win32::Foundation::{CloseHandle, HANDLE, WAIT_OBJECT_0};
 use windows::Win32::Media::Audio::{
-  AUDCLNT_BUFFERFLAGS_SILENT, AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
-  AUDCLNT_STREAMFLAGS_LOOPBACK, IAudioCaptureClient, IAudioClient, IMMDeviceEnumerator,
-  MMDeviceEnumerator, eConsole, eRender,
+  AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY, AUDCLNT_BUFFERFLAGS_SILENT,
+  AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR, AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+  AUDCLNT_STREAMFLAGS_LOOPBACK, DEVICE_STATE_ACTIVE, IAudioCaptureClient, IAudioClient, IMMDevice,
+  IMMDeviceEnumerator, IMMEndpoint, MMDeviceEnumerator, WAVE_FORMAT_PCM, WAVEFORMATEX,
+  WAVEFORMATEXTENSIBLE, eAll, eCapture, eConsole, eRender,
 };
+use windows::Win32::Media::KernelStreaming::{KSDATAFORMAT_SUBTYPE_PCM, WAVE_FORMAT_EXTENSIBLE};
+use windows::Win32::Media::Multimedia::{KSDATAFORMAT_SUBTYPE_IEEE_FLOAT, WAVE_FORMAT_IEEE_FLOAT};
 use windows::Win32::System::Com::{
-  CLSCTX_ALL, COINIT_MULTITHREADED, CoCreateInstance, CoInitializeEx, CoTaskMemFree, CoUninitialize,
+  CLSCTX_ALL, COINIT_MULTITHREADED, CoCreateInstance, CoInitializeEx, CoTaskMemFree,
+  CoUninitialize, STGM_READ,
 };
+use windows::Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency};
 use windows::Win32::System::Threading::{CreateEventW, WaitForSingleObject};
+use windows::core::{HSTRING, Interface};
 
 use crate::audio::AudioConfig;
-use crate::audio::backend::{AudioBackend, AudioPacket};
+use crate::audio::backend::{AudioBackend, AudioPacket, AudioSender, DeviceInfo};
+use crate::audio::format::{Endian, SampleFormat, SampleType};
 
 pub struct WasapiBackend {
   config: AudioConfig,
+  // endpoint id, none follows the default render endpoint
+  device: Option<String>,
   stop: Arc<AtomicBool>,
 }
 
 impl WasapiBackend {
-  pub fn new(config: AudioConfig, stop: Arc<AtomicBool>) -> Self {
-    Self { config, stop }
+  pub fn new(config: AudioConfig, device: Option<String>, stop: Arc<AtomicBool>) -> Self {
+    Self {
+      config,
+      device,
+      stop,
+    }
+  }
+
+  /// Every active endpoint, render endpoints as loopback and capture endpoints
+  /// as inputs
+  pub fn devices() -> Result<Vec<DeviceInfo>, anyhow::Error> {
+    unsafe {
+      CoInitializeEx(None, COINIT_MULTITHREADED).ok()?;
+      let result = enumerate_devices();
+      CoUninitialize();
+      result
+    }
   }
 }
 
+#[async_trait]
 impl AudioBackend for WasapiBackend {
-  type Error = anyhow::Error;
-
-  async fn run(self, tx: Input<AudioPacket>) -> Result<(), Self::Error> {
-    task::spawn_blocking(move || capture_loop(self.config, self.stop, tx)).await??;
+  async fn run(self: Box<Self>, tx: AudioSender) -> Result<(), anyhow::Error> {
+    task::spawn_blocking(move || capture_loop(self.config, self.device, self.stop, tx)).await??;
     Ok(())
   }
 }
 
 fn capture_loop(
   _config: AudioConfig,
+  device_id: Option<String>,
   stop: Arc<AtomicBool>,
-  tx: Input<AudioPacket>,
+  tx: AudioSender,
 ) -> Result<(), anyhow::Error> {
   unsafe {
     // init com
@@ -56,9 +82,17 @@ fn capture_loop(
     if event_handle.is_invalid() {
       return Err(anyhow::anyhow!("Failed to create event"));
     }
-    // get default loopback device
+    // get the requested device, or the default loopback device
     let enumerator: IMMDeviceEnumerator = CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
-    let device = enumerator.GetDefaultAudioEndpoint(eRender, eConsole)?;
+    let device = match &device_id {
+      Some(id) => enumerator.GetDevice(&HSTRING::from(id.as_str()))?,
+      None => enumerator.GetDefaultAudioEndpoint(eRender, eConsole)?,
+    };
+    // render endpoints are captured in loopback, capture endpoints directly
+    let mut stream_flags = AUDCLNT_STREAMFLAGS_EVENTCALLBACK;
+    if device.cast::<IMMEndpoint>()?.GetDataFlow()? == eRender {
+      stream_flags |= AUDCLNT_STREAMFLAGS_LOOPBACK;
+    }
     // activate client
     let audio_client: IAudioClient = device.Activate(CLSCTX_ALL, None)?;
 
@@ -67,13 +101,18 @@ fn capture_loop(
     let pwfx = &*pwfx_ptr;
     let sample_rate = pwfx.nSamplesPerSec;
     let channels = pwfx.nChannels;
+    let format = mix_sample_format(pwfx)?;
+    info!(
+      "wasapi mix format is {} ({} Hz, {} channels)...",
+      format, sample_rate, channels
+    );
 
     // 20ms for low latency while maintaining stability
     let hns_buffer = 200_000i64;
     // init with event callback
     audio_client.Initialize(
       AUDCLNT_SHAREMODE_SHARED,
-      AUDCLNT_STREAMFLAGS_LOOPBACK | AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+      stream_flags,
       hns_buffer,
       0,
       pwfx_ptr,
@@ -92,6 +131,7 @@ fn capture_loop(
       &capture_client,
       sample_rate,
       channels,
+      format,
       stop,
       tx,
     );
@@ -112,15 +152,17 @@ fn capture_loop_inner(
   capture_client: &IAudioCaptureClient,
   sample_rate: u32,
   channels: u16,
+  format: SampleFormat,
   stop: Arc<AtomicBool>,
-  mut tx: Input<AudioPacket>,
+  mut tx: AudioSender,
 ) -> Result<(), anyhow::Error> {
   unsafe {
     // pre alloc buffers
     let max_frames = audio_client.GetBufferSize()?;
     let max_len = (max_frames as usize).saturating_mul(channels as usize);
-    let mut samples_buf: Vec<f32> = Vec::with_capacity(max_len);
-    let mut batch_buffer: Vec<AudioPacket> = Vec::with_capacity(8);
+    let mut packet = AudioPacket::new(sample_rate as f32, channels, max_len);
+    let mut qpc_frequency = 0i64;
+    QueryPerformanceFrequency(&mut qpc_frequency)?;
 
     // start streaming
     audio_client.Start()?;
@@ -145,49 +187,56 @@ fn capture_loop_inner(
           }
           last_process = Instant::now();
 
-          // process all available buffers
-          batch_buffer.clear();
-
+          // send every available buffer on, in order
           loop {
             let mut data_ptr: *mut u8 = ptr::null_mut();
             let mut frames_avail = 0u32;
             let mut flags = 0u32;
+            let mut device_position = 0u64;
+            let mut qpc_position = 0u64;
 
-            match capture_client.GetBuffer(&mut data_ptr, &mut frames_avail, &mut flags, None, None)
-            {
+            match capture_client.GetBuffer(
+              &mut data_ptr,
+              &mut frames_avail,
+              &mut flags,
+              Some(&mut device_position),
+              Some(&mut qpc_position),
+            ) {
               Ok(()) => {
                 if frames_avail == 0 {
                   break;
                 }
 
                 let len = (frames_avail as usize).saturating_mul(channels as usize);
-                let is_silent = (flags & (AUDCLNT_BUFFERFLAGS_SILENT.0 as u32)) != 0;
+                packet.is_silent = (flags & (AUDCLNT_BUFFERFLAGS_SILENT.0 as u32)) != 0;
 
-                samples_buf.clear();
-                if is_silent {
-                  samples_buf.resize(len, 0.0);
+                packet.samples.clear();
+                if packet.is_silent {
+                  packet.samples.resize(len, 0.0);
                 } else {
-                  let slice = std::slice::from_raw_parts(data_ptr as *const f32, len);
-                  samples_buf.extend_from_slice(slice);
+                  let bytes = len.saturating_mul(format.bytes_per_sample());
+                  let slice = std::slice::from_raw_parts(data_ptr as *const u8, bytes);
+                  format.decode_into(slice, &mut packet.samples);
                 }
 
                 capture_client.ReleaseBuffer(frames_avail)?;
 
-                batch_buffer.push(AudioPacket {
-                  samples: std::mem::take(&mut samples_buf),
-                  sample_rate: sample_rate as f32,
-                  channels,
-                  is_silent,
-                });
+                packet.position = device_position;
+                packet.discontinuity =
+                  (flags & (AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32)) != 0;
+                packet.glitch = (flags & (AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR.0 as u32)) != 0;
+                packet.timestamp = if packet.glitch {
+                  // the qpc position can't be trusted, fall back to arrival
+                  Instant::now() - packet.duration()
+                } else {
+                  qpc_instant(qpc_position, qpc_frequency)?
+                };
+
+                tx.write(&mut packet);
               }
               Err(_) => break,
             }
           }
-
-          // send batched packets
-          if let Some(packet) = batch_buffer.drain(..).next_back() {
-            tx.write(packet);
-          }
         }
         _ => continue, // timeout - check stop flag on next iteration
       }
@@ -196,3 +245,119 @@ fn capture_loop_inner(
     Ok(())
   }
 }
+
+/// Turn a capture time in 100ns qpc units into an `Instant`, by how long ago
+/// it was
+unsafe fn qpc_instant(qpc_position: u64, qpc_frequency: i64) -> Result<Instant, anyhow::Error> {
+  let mut counter = 0i64;
+  unsafe { QueryPerformanceCounter(&mut counter)? };
+  let now = Instant::now();
+  let now_100ns = counter as i128 * 10_000_000 / qpc_frequency.max(1) as i128;
+  let age = (now_100ns - qpc_position as i128).max(0) as u64;
+  Ok(now - Duration::from_nanos(age * 100))
+}
+
+/// Work out how the mix format stores samples, wasapi is always little-endian
+fn mix_sample_format(pwfx: &WAVEFORMATEX) -> Result<SampleFormat, anyhow::Error> {
+  let tag = pwfx.wFormatTag as u32;
+  let bits = pwfx.wBitsPerSample;
+  let is_float = match tag {
+    WAVE_FORMAT_PCM => false,
+    WAVE_FORMAT_IEEE_FLOAT => true,
+    WAVE_FORMAT_EXTENSIBLE => {
+      // the extensible header follows on from the base one
+      let ext = unsafe { &*(pwfx as *const WAVEFORMATEX).cast::<WAVEFORMATEXTENSIBLE>() };
+      let sub_format = ext.SubFormat;
+      if sub_format == KSDATAFORMAT_SUBTYPE_IEEE_FLOAT {
+        true
+      } else if sub_format == KSDATAFORMAT_SUBTYPE_PCM {
+        false
+      } else {
+        return Err(anyhow::anyhow!(
+          "unsupported mix format subtype {:?}",
+          sub_format
+        ));
+      }
+    }
+    _ => return Err(anyhow::anyhow!("unsupported mix format tag {}", tag)),
+  };
+
+  // 24 bit samples in 32 bit containers are left aligned, so they read as i32
+  let sample_type = match (is_float, bits) {
+    (false, 16) => SampleType::I16,
+    (false, 24) => SampleType::I24,
+    (false, 32) => SampleType::I32,
+    (true, 32) => SampleType::F32,
+    (true, 64) => SampleType::F64,
+    _ => {
+      return Err(anyhow::anyhow!(
+        "unsupported {} bit {} mix format",
+        bits,
+        if is_float { "float" } else { "integer" }
+      ));
+    }
+  };
+  Ok(SampleFormat::new(sample_type, Endian::Little))
+}
+
+unsafe fn enumerate_devices() -> Result<Vec<DeviceInfo>, anyhow::Error> {
+  unsafe {
+    let enumerator: IMMDeviceEnumerator = CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)?;
+    let default_render = enumerator
+      .GetDefaultAudioEndpoint(eRender, eConsole)
+      .ok()
+      .and_then(|d| device_id(&d).ok());
+    let default_capture = enumerator
+      .GetDefaultAudioEndpoint(eCapture, eConsole)
+      .ok()
+      .and_then(|d| device_id(&d).ok());
+
+    let collection = enumerator.EnumAudioEndpoints(eAll, DEVICE_STATE_ACTIVE)?;
+    let mut devices = Vec::new();
+    for i in 0..collection.GetCount()? {
+      let device = collection.Item(i)?;
+      let id = device_id(&device)?;
+      let loopback = device.cast::<IMMEndpoint>()?.GetDataFlow()? == eRender;
+      let is_default = if loopback {
+        default_render.as_ref() == Some(&id)
+      } else {
+        default_capture.as_ref() == Some(&id)
+      };
+      let name = device
+        .OpenPropertyStore(STGM_READ)
+        .and_then(|store| store.GetValue(&PKEY_Device_FriendlyName))
+        .map(|value| value.to_string())
+        .unwrap_or_else(|_| id.clone());
+
+      // the shared mode mix format is what we would capture at
+      let format = device
+        .Activate::<IAudioClient>(CLSCTX_ALL, None)
+        .and_then(|client| client.GetMixFormat())
+        .ok()
+        .map(|pwfx_ptr| {
+          let format = ((*pwfx_ptr).nSamplesPerSec, (*pwfx_ptr).nChannels);
+          CoTaskMemFree(Some(pwfx_ptr.cast()));
+          format
+        });
+
+      devices.push(DeviceInfo {
+        id,
+        name,
+        loopback,
+        is_default,
+        sample_rate: format.map(|(rate, _)| rate),
+        channels: format.map(|(_, channels)| channels),
+      });
+    }
+    Ok(devices)
+  }
+}
+
+unsafe fn device_id(device: &IMMDevice) -> Result<String, anyhow::Error> {
+  unsafe {
+    let raw = device.GetId()?;
+    let id = raw.to_string();
+    CoTaskMemFree(Some(raw.0 as *const _));
+    Ok(id?)
+  }
+}
@@ -0,0 +1,238 @@
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+use apodize::{cosine_iter, hamming_iter, hanning_iter, nuttall_iter};
+
+const DEFAULT_KAISER_BETA: f64 = 9.0;
+const DEFAULT_GAUSSIAN_SIGMA: f64 = 0.4;
+
+/// Window applied to each block before the fft, trading how sharply a tone
+/// shows up against how far its leakage reaches
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WindowFunction {
+  Rectangular,
+  Hann,
+  Hamming,
+  Blackman,
+  /// Four term, for the most dynamic range
+  BlackmanHarris,
+  Nuttall,
+  /// Five term, for reading the level of a tone accurately
+  FlatTop,
+  Kaiser(f64),
+  /// `sigma` as a fraction of half the window
+  Gaussian(f64),
+}
+
+impl FromStr for WindowFunction {
+  type Err = anyhow::Error;
+
+  /// Parses `rectangular`, `hann`, `hamming`, `blackman`, `blackman-harris`,
+  /// `nuttall`, `flat-top`, `kaiser[:BETA]` and `gaussian[:SIGMA]`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (kind, param) = s.split_once(':').unwrap_or((s, ""));
+    let kind = kind.to_ascii_lowercase();
+    let number = |default: f64| {
+      if param.is_empty() {
+        return Ok(default);
+      }
+      param
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite() && *v > 0.0)
+        .ok_or_else(|| anyhow::anyhow!("invalid number '{}' in window '{}'", param, s))
+    };
+
+    let window = match kind.as_str() {
+      "rectangular" | "rect" | "none" => WindowFunction::Rectangular,
+      "hann" | "hanning" => WindowFunction::Hann,
+      "hamming" => WindowFunction::Hamming,
+      "blackman" => WindowFunction::Blackman,
+      "blackman-harris" => WindowFunction::BlackmanHarris,
+      "nuttall" => WindowFunction::Nuttall,
+      "flat-top" | "flattop" => WindowFunction::FlatTop,
+      "kaiser" => WindowFunction::Kaiser(number(DEFAULT_KAISER_BETA)?),
+      "gaussian" => WindowFunction::Gaussian(number(DEFAULT_GAUSSIAN_SIGMA)?),
+      _ => {
+        return Err(anyhow::anyhow!(
+          "unknown window '{}', expected rectangular, hann, hamming, blackman, blackman-harris, nuttall, flat-top, kaiser or gaussian",
+          s
+        ));
+      }
+    };
+    if !param.is_empty() && !matches!(kind.as_str(), "kaiser" | "gaussian") {
+      return Err(anyhow::anyhow!("window '{}' takes no parameter", kind));
+    }
+    Ok(window)
+  }
+}
+
+impl WindowFunction {
+  /// The window over `size` samples
+  pub fn coefficients(&self, size: usize) -> Vec<f32> {
+    // the cosine windows need at least two points
+    if size < 2 {
+      return vec![1.0; size];
+    }
+    let position = |n: usize| n as f64 / (size - 1) as f64;
+    let values: Vec<f64> = match *self {
+      WindowFunction::Rectangular => vec![1.0; size],
+      WindowFunction::Hann => hanning_iter(size).collect(),
+      WindowFunction::Hamming => hamming_iter(size).collect(),
+      WindowFunction::Blackman => cosine_iter(0.42, 0.5, 0.08, 0.0, size).collect(),
+      // apodize's blackman is the four term blackman-harris
+      WindowFunction::BlackmanHarris => {
+        cosine_iter(0.35875, 0.48829, 0.14128, 0.01168, size).collect()
+      }
+      WindowFunction::Nuttall => nuttall_iter(size).collect(),
+      WindowFunction::FlatTop => {
+        const A: [f64; 5] = [
+          0.21557895,
+          0.41663158,
+          0.277263158,
+          0.083578947,
+          0.006947368,
+        ];
+        (0..size)
+          .map(|n| {
+            let x = 2.0 * PI * position(n);
+            A[0] - A[1] * x.cos() + A[2] * (2.0 * x).cos() - A[3] * (3.0 * x).cos()
+              + A[4] * (4.0 * x).cos()
+          })
+          .collect()
+      }
+      WindowFunction::Kaiser(beta) => (0..size)
+        .map(|n| {
+          let x = 2.0 * position(n) - 1.0;
+          bessel_i0(beta * (1.0 - x * x).max(0.0).sqrt()) / bessel_i0(beta)
+        })
+        .collect(),
+      WindowFunction::Gaussian(sigma) => (0..size)
+        .map(|n| {
+          let x = (2.0 * position(n) - 1.0) / sigma;
+          (-0.5 * x * x).exp()
+        })
+        .collect(),
+    };
+    values.into_iter().map(|v| v as f32).collect()
+  }
+}
+
+/// Coherent gain of `window`, the fraction of a tone's amplitude that
+/// survives it
+pub fn coherent_gain(window: &[f32]) -> f32 {
+  let sum: f32 = window.iter().sum();
+  sum / window.len().max(1) as f32
+}
+
+/// Equivalent noise bandwidth of `window` in bins, how much wider than a bin
+/// it lets noise in
+pub fn enbw(window: &[f32]) -> f32 {
+  let sum: f32 = window.iter().sum();
+  let sum_sq: f32 = window.iter().map(|w| w * w).sum();
+  if sum == 0.0 {
+    return 1.0;
+  }
+  window.len() as f32 * sum_sq / (sum * sum)
+}
+
+/// Zeroth order modified bessel function of the first kind, by its series
+pub fn bessel_i0(x: f64) -> f64 {
+  let mut sum = 1.0;
+  let mut term = 1.0;
+  let half_x = x / 2.0;
+  for k in 1..64 {
+    term *= half_x / k as f64;
+    sum += term * term;
+    if term * term < sum * 1e-12 {
+      break;
+    }
+  }
+  sum
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audio::processor::{AudioProcessor, Channel, Magnitude};
+  use crate::audio::test_config;
+
+  /// Level in db of the loudest bar for a full scale sine in the middle of
+  /// the bar around 1kHz, seen through `window`
+  fn tone_level(window: WindowFunction) -> f32 {
+    let mut config = test_config();
+    // bars wide enough for the broadest main lobe
+    config.fft_size = 4096;
+    config.hop_size = 1024;
+    config.bar_count = 32;
+    config.window = window;
+    // headroom so reading high isn't hidden by the clamp
+    config.magnitude = Magnitude::Decibels {
+      floor: -90.0,
+      ceiling: 10.0,
+    };
+    let edges = config
+      .scale
+      .edges(config.bar_count, config.min_freq, config.max_freq);
+    let bar = edges.windows(2).find(|e| e[1] > 1000.0).unwrap();
+    let freq = config.scale.centre(bar[0], bar[1]);
+
+    let rate = 48_000.0;
+    let samples: Vec<f32> = (0..48_000)
+      .map(|n| (2.0 * std::f32::consts::PI * freq * n as f32 / rate).sin())
+      .collect();
+    let mut processor = AudioProcessor::new(config);
+    processor.process(&samples, 1, rate);
+    let peak = processor
+      .spectrum(Channel::Mono)
+      .iter()
+      .copied()
+      .fold(0.0, f32::max);
+    -90.0 + 100.0 * peak
+  }
+
+  #[test]
+  fn a_tone_reads_the_same_whatever_the_window() {
+    let reference = tone_level(WindowFunction::Rectangular);
+    assert!(reference.abs() < 0.5, "rectangular read {}db", reference);
+    for window in [
+      WindowFunction::Hann,
+      WindowFunction::Hamming,
+      WindowFunction::Blackman,
+      WindowFunction::Nuttall,
+      WindowFunction::FlatTop,
+      WindowFunction::Kaiser(8.0),
+      WindowFunction::Gaussian(0.4),
+    ] {
+      let level = tone_level(window);
+      assert!(
+        (level - reference).abs() < 0.5,
+        "{:?} read {}db against {}db",
+        window,
+        level,
+        reference
+      );
+    }
+  }
+
+  #[test]
+  fn hann_keeps_half_and_lets_in_a_bin_and_a_half() {
+    let hann = WindowFunction::Hann.coefficients(4096);
+    assert!((coherent_gain(&hann) - 0.5).abs() < 1e-3);
+    assert!((enbw(&hann) - 1.5).abs() < 1e-3);
+  }
+
+  #[test]
+  fn parameters_parse_whatever_the_case() {
+    assert_eq!(
+      "Kaiser:5".parse::<WindowFunction>().unwrap(),
+      WindowFunction::Kaiser(5.0)
+    );
+    assert_eq!(
+      "GAUSSIAN:0.3".parse::<WindowFunction>().unwrap(),
+      WindowFunction::Gaussian(0.3)
+    );
+    assert!("Hann:2".parse::<WindowFunction>().is_err());
+  }
+}
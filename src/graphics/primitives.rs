@@ -80,6 +80,7 @@ pub fn draw_text(
           buffer[(y + 3) * width + pos_x + 2] = color;
           buffer[(y + 7) * width + pos_x + 2] = color;
         }
+        '-' => draw_rect(buffer, width, height, (pos_x, y + 4), 5, 1, color),
         _ => {}
       }
       pos_x += 8;
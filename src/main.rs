@@ -1,5 +1,10 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use std::time::Duration;
+
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+
 use tracing::info;
 
 use tracing_subscriber::filter::LevelFilter;
@@ -11,26 +16,194 @@ mod visualisation;
 
 use app::App;
 use audio::AudioConfig;
+use audio::agc::AgcConfig;
+use audio::filter::FilterSpec;
+use audio::processor::{Channel, Magnitude, Transform};
+#[cfg(feature = "file")]
+use audio::recorder::RecordOptions;
+use audio::registry::{BACKENDS, BackendOptions};
+use audio::scale::FrequencyScale;
+use audio::window::WindowFunction;
+
+// bars shown, unless the scale has its own
+const BAR_COUNT: usize = 64;
+
+/// Audio visualizer, frequency and time
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+  #[command(flatten)]
+  backend: BackendOptions,
+  #[cfg(feature = "file")]
+  #[command(flatten)]
+  record: RecordOptions,
+  /// List the audio backends in this build, in fallback order, and exit
+  #[arg(long)]
+  list_backends: bool,
+  /// List the devices of the chosen backend, or of every backend, and exit
+  #[arg(long)]
+  list_devices: bool,
+  /// Channel to visualise: mono, mid, side, left, right or a number from 1
+  #[arg(long, default_value = "mono")]
+  channel: Channel,
+  /// Rate to resample audio to before analysis so visuals match across
+  /// devices, 0 analyses at whatever rate the device runs
+  #[arg(long, value_name = "HZ", default_value_t = 48_000)]
+  analysis_rate: u32,
+  /// Level automatic gain brings the rms of the bars to, 1.0 being the top
+  /// of the display
+  #[arg(long, value_name = "LEVEL", default_value_t = 0.4)]
+  agc_target: f32,
+  /// How quickly gain comes down when the audio gets louder, in ms
+  #[arg(long, value_name = "MS", default_value_t = 100)]
+  agc_attack: u64,
+  /// How quickly gain comes back up when the audio gets quieter, in ms
+  #[arg(long, value_name = "MS", default_value_t = 4000)]
+  agc_release: u64,
+  /// Most automatic gain boosts quiet audio by, in db, 0 putting a full scale
+  /// sine at the top
+  #[arg(long, value_name = "DB", default_value_t = 60.0)]
+  agc_max_gain: f32,
+  /// Frames in each analysis window, more for finer frequency detail, fewer
+  /// for faster response
+  #[arg(long, value_name = "FRAMES", default_value_t = 1024)]
+  fft_size: usize,
+  /// Frames between the starts of successive windows, a quarter of
+  /// --fft-size (75% overlap) by default
+  #[arg(long, value_name = "FRAMES")]
+  hop: Option<usize>,
+  /// Zero pad each window out to this many times its length, for finer bins
+  /// without a longer window
+  #[arg(long, value_name = "FACTOR", default_value_t = 1)]
+  zero_padding: usize,
+  /// Window applied before the fft: rectangular, hann, hamming, blackman,
+  /// blackman-harris, nuttall, flat-top, kaiser[:BETA] or gaussian[:SIGMA]
+  #[arg(long, default_value = "hann")]
+  window: WindowFunction,
+  /// How the spectrum is scaled: approximate or exact magnitudes, power, each
+  /// with automatic gain, or db[:FLOOR[:CEILING]] for a fixed dbfs range
+  #[arg(long, value_name = "MODE", default_value = "approximate")]
+  magnitude: Magnitude,
+  /// What the bars come from: fft, or cqt[:BINS_PER_OCTAVE] for a constant-q
+  /// transform, finer in the bass and quicker in the treble
+  #[arg(long, value_name = "TRANSFORM", default_value = "fft")]
+  transform: Transform,
+  /// How bars are spread over frequency: linear, log, mel, bark, erb or
+  /// octave[:N] for standard 1/N octave bands, N being 1, 3, 6 or 12
+  #[arg(long, default_value = "log")]
+  scale: FrequencyScale,
+  /// Lowest frequency shown, in Hz
+  #[arg(long, value_name = "HZ", default_value_t = 20.0)]
+  min_freq: f32,
+  /// Highest frequency shown, in Hz
+  #[arg(long, value_name = "HZ", default_value_t = 20_000.0)]
+  max_freq: f32,
+  /// Filters run over the audio before analysis, in order: highpass:HZ[:Q],
+  /// lowpass:HZ[:Q], notch:HZ[:Q], preemphasis[:COEF], a-weighting or
+  /// c-weighting
+  #[arg(long = "filter", value_name = "FILTER", value_delimiter = ',')]
+  filters: Vec<FilterSpec>,
+  /// Also log debug output, such as capture latency and dropped packets
+  #[arg(short, long)]
+  verbose: bool,
+}
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), anyhow::Error> {
+  let args = Args::parse();
+  if let Err(e) = args.backend.check_inputs() {
+    Args::command().error(ErrorKind::ArgumentConflict, e).exit();
+  }
+
+  if args.list_backends {
+    for entry in BACKENDS {
+      println!("{:<10} {}", entry.name, entry.description);
+    }
+    return Ok(());
+  }
+
+  if args.list_devices {
+    list_devices(args.backend.backend.as_deref());
+    return Ok(());
+  }
+
   tracing_subscriber::fmt()
-    .with_max_level(LevelFilter::INFO)
+    .with_max_level(if args.verbose {
+      LevelFilter::DEBUG
+    } else {
+      LevelFilter::INFO
+    })
     .with_target(false)
     .init();
 
   // default config...
+  let fft_size = args.fft_size.clamp(64, 1 << 16);
+  let min_freq = args.min_freq.max(1.0);
+  let max_freq = args.max_freq.max(min_freq + 1.0);
   let config = AudioConfig {
-    fft_size: 1024,
+    fft_size,
+    hop_size: args.hop.unwrap_or(fft_size / 4).clamp(1, fft_size),
+    zero_padding: args.zero_padding.clamp(1, 16),
+    window: args.window,
+    magnitude: args.magnitude,
+    transform: args.transform,
     buffer_size: 2048,
-    bar_count: 64,
+    analysis_rate: (args.analysis_rate > 0).then_some(args.analysis_rate),
+    // a little over 5s of 48kHz stereo
+    history_size: 1 << 19,
+    bar_count: args.scale.edges(BAR_COUNT, min_freq, max_freq).len() - 1,
+    scale: args.scale,
+    min_freq,
+    max_freq,
+    channel: args.channel,
+    agc: AgcConfig {
+      target: args.agc_target.clamp(0.01, 1.0),
+      attack: Duration::from_millis(args.agc_attack),
+      release: Duration::from_millis(args.agc_release),
+      max_gain: 10.0f32.powf(args.agc_max_gain / 20.0),
+    },
+    filters: args.filters,
   };
 
   info!("audio visualizer spinning up...");
 
-  let mut app = App::new(config)?;
+  let mut app = App::new(
+    config,
+    &args.backend,
+    #[cfg(feature = "file")]
+    &args.record,
+  )?;
   app.run().await?;
 
   info!("audio visualizer spinning down...");
   Ok(())
 }
+
+fn list_devices(backend: Option<&str>) {
+  let entries = BACKENDS
+    .iter()
+    .filter(|entry| backend.is_none_or(|name| entry.name.eq_ignore_ascii_case(name)));
+  for entry in entries {
+    println!("{}:", entry.name);
+    match entry.devices() {
+      Ok(devices) if devices.is_empty() => println!("  (no devices)"),
+      Ok(devices) => {
+        for device in devices {
+          let format = match (device.sample_rate, device.channels) {
+            (Some(rate), Some(channels)) => format!(", {} Hz, {} ch", rate, channels),
+            _ => String::new(),
+          };
+          println!(
+            "  {} {} - {} ({}{})",
+            if device.is_default { "*" } else { " " },
+            device.id,
+            device.name,
+            if device.loopback { "Loopback" } else { "Input" },
+            format
+          );
+        }
+      }
+      Err(e) => println!("  unavailable - {}", e),
+    }
+  }
+}
@@ -1,4 +1,5 @@
 use std::cell::Cell;
+use std::time::{Duration, Instant};
 
 use chrono::{Local, Timelike};
 
@@ -7,17 +8,32 @@ use rand::Rng;
 use crate::audio::AudioConfig;
 use crate::audio::backend::AudioPacket;
 use crate::audio::processor::AudioProcessor;
+use crate::audio::supervisor::ConnectionState;
 
 use crate::graphics::renderer::Renderer;
 
 use crate::visualisation::spectrum::SpectrumAnalyzer;
 use crate::visualisation::waveform::WaveformDisplay;
 
+// how long the glitch marker stays up after a glitch
+const GLITCH_HOLD: Duration = Duration::from_millis(500);
+
 pub struct Visualiser {
   processor: AudioProcessor,
   spectrum: SpectrumAnalyzer,
   waveform: WaveformDisplay,
   config: AudioConfig,
+  // the followed channel of the latest packet, reused between packets
+  block: Vec<f32>,
+  // the last window of the followed channel, oldest first
+  signal: Vec<f32>,
+  connection: ConnectionState,
+  // when the last glitched or discontinuous packet came through
+  last_glitch: Option<Instant>,
+  // whether the capture is being saved
+  recording: bool,
+  // capture time of the newest sample analysed
+  captured: Option<Instant>,
   // width, height
   window_dims: Cell<(usize, usize)>,
 }
@@ -29,24 +45,63 @@ impl Visualiser {
       spectrum: SpectrumAnalyzer::new(config.bar_count),
       waveform: WaveformDisplay::new(initial_width),
       config,
+      block: Vec::new(),
+      signal: Vec::new(),
+      connection: ConnectionState::default(),
+      last_glitch: None,
+      recording: false,
+      captured: None,
       window_dims: Cell::from((initial_width, 0)),
     }
   }
 
   pub fn update(&mut self, packet: &AudioPacket) {
+    if packet.glitch || packet.discontinuity {
+      self.last_glitch = Some(Instant::now());
+    }
+    if !packet.samples.is_empty() {
+      self.captured = Some(packet.timestamp + packet.duration());
+    }
+
+    let channel = self.config.channel;
     if packet.is_silent {
-      self.processor.process(&[], packet.sample_rate);
+      self
+        .processor
+        .process(&[], packet.channels, packet.sample_rate);
       self.waveform.decay();
     } else {
-      let mono_samples = self.mix_to_mono(&packet.samples, packet.channels);
-      // process fft...
-      self.processor.process(&mono_samples, packet.sample_rate);
+      // process fft, for the channels we draw...
+      self
+        .processor
+        .process(&packet.samples, packet.channels, packet.sample_rate);
+      // packets are a hop long, so keep a window's worth to draw
+      channel.extract(&packet.samples, packet.channels, &mut self.block);
+      self.signal.extend_from_slice(&self.block);
+      let keep = self.config.fft_size.min(self.config.buffer_size);
+      let excess = self.signal.len().saturating_sub(keep);
+      self.signal.drain(..excess);
       self
         .waveform
-        .update(&mono_samples, self.processor.fft_output());
+        .update(&self.signal, self.processor.fft_output(channel));
     }
     // update spectrum with processed...
-    self.spectrum.update(self.processor.spectrum(), self.window_dims.get().1);
+    self
+      .spectrum
+      .update(self.processor.spectrum(channel), self.window_dims.get().1);
+  }
+
+  pub fn set_connection(&mut self, state: ConnectionState) {
+    self.connection = state;
+  }
+
+  #[cfg_attr(not(feature = "file"), allow(dead_code))]
+  pub fn set_recording(&mut self, recording: bool) {
+    self.recording = recording;
+  }
+
+  /// Capture time of the newest sample that made it into the visuals
+  pub fn captured(&self) -> Option<Instant> {
+    self.captured
   }
 
   pub fn resize(&mut self, width: usize) {
@@ -74,28 +129,56 @@ impl Visualiser {
 
     let time_str = std::str::from_utf8(&time_buffer).unwrap();
     renderer.draw_text(time_str, 10, 10, 0x00FFFFFF);
+
+    self.render_connection(renderer, now.timestamp_subsec_millis());
+    self.render_glitch(renderer);
+    self.render_recording(renderer);
+    self.render_gain(renderer);
   }
 
-  fn mix_to_mono(&self, samples: &[f32], channels: u16) -> Vec<f32> {
-    let frame_count = samples.len() / channels as usize;
-    let mut mono = vec![0.0; frame_count.min(self.config.buffer_size)];
-
-    for (i, m) in mono.iter_mut().enumerate() {
-      let mut sum = 0.0;
-      for ch in 0..channels as usize {
-        let idx = i * channels as usize + ch;
-        if idx < samples.len() {
-          sum += samples[idx];
-        }
-      }
-      *m = sum / channels as f32;
+  /// Automatic gain in db, dimmed, left of the recording marker
+  fn render_gain(&self, renderer: &mut Renderer) {
+    let Some(gain) = self.processor.gain() else {
+      return;
+    };
+    let db = 20.0 * gain.log10();
+    let text = (db.round() as i32).to_string();
+    let (width, _) = renderer.dimensions();
+    let x = width.saturating_sub(27 + 8 * text.len());
+    renderer.draw_text(&text, x, 10, 0x00808080);
+  }
+
+  /// Red square in the top right while the capture is being saved
+  fn render_recording(&self, renderer: &mut Renderer) {
+    if self.recording {
+      let (width, _) = renderer.dimensions();
+      renderer.draw_rect(width.saturating_sub(17), 11, 7, 7, 0x00FF3030);
     }
+  }
 
-    mono
+  /// Red bar under the clock for a moment after audio was lost or glitched
+  fn render_glitch(&self, renderer: &mut Renderer) {
+    if self
+      .last_glitch
+      .is_some_and(|glitch| glitch.elapsed() < GLITCH_HOLD)
+    {
+      renderer.draw_rect(10, 22, 37, 2, 0x00FF3030);
+    }
+  }
+
+  /// Blinking amber square and attempt count beside the clock while the
+  /// backend is being restarted
+  fn render_connection(&self, renderer: &mut Renderer, millis: u32) {
+    if let ConnectionState::Reconnecting { attempt } = self.connection {
+      if millis < 500 {
+        renderer.draw_rect(56, 11, 7, 7, 0x00FFB000);
+      }
+      renderer.draw_text(&attempt.to_string(), 68, 10, 0x00FFB000);
+    }
   }
 
   fn render_particles(&self, renderer: &mut Renderer) {
-    let total_energy: f32 = self.processor.spectrum().iter().sum();
+    let total_energy: f32 = self.processor.spectrum(self.config.channel).iter().sum();
     let particle_count = (total_energy * 100.0) as usize;
     let (width, height) = renderer.dimensions();
 
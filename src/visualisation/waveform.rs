@@ -5,10 +5,10 @@ struct BandParams {
   colour: u32,
 }
 
-// audio bands... (start, end, colour)
+// audio bands... (start, end, colour), in bins of a 1024 point fft
 const BANDS: &[(usize, usize, u32)] = &[
-  (0, 4, 0x00FF0000), // sub-bass - red
-  (4, 12, 0x00FF7F00), // bass - orange
+  (0, 4, 0x00FF0000),   // sub-bass - red
+  (4, 12, 0x00FF7F00),  // bass - orange
   (12, 24, 0x00FFFF00), // low-mid - yellow
   (24, 40, 0x0000FF00), // mid - green
   (40, 56, 0x000000FF), // high-mid - blue
@@ -109,6 +109,10 @@ impl WaveformDisplay {
 
 #[inline]
 fn calculate_band_energy(fft_output: &[f32], start: usize, end: usize) -> f32 {
+  // longer or padded ffts have more, narrower bins over the same range
+  let scale = (fft_output.len() as f32 / 512.0).max(f32::EPSILON);
+  let start = (start as f32 * scale).round() as usize;
+  let end = ((end as f32 * scale).round() as usize).max(start + 1);
   let energy: f32 = fft_output
     .iter()
     .take(end.min(fft_output.len()))